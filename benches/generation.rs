@@ -0,0 +1,117 @@
+// Baseline for the generation path: parsing each kind of link, and whole configs with
+// and without a template. Run with `cargo bench`.
+use base64::{Engine as _, engine::general_purpose};
+use criterion::{BenchmarkId, Criterion, black_box, criterion_group, criterion_main};
+use txt2sub::{ClashGenOptions, ProxyList, generate_clash_yaml, parse_link};
+
+const REGIONS: &[&str] = &["香港", "Japan", "US Los Angeles", "新加坡", "Taiwan", "Germany"];
+
+const TEMPLATE: &str = r#"
+mixed-port: 7890
+allow-lan: false
+mode: rule
+log-level: info
+dns:
+  enable: true
+  enhanced-mode: fake-ip
+  nameserver:
+    - https://doh.pub/dns-query
+    - https://dns.alidns.com/dns-query
+proxies:
+proxy-groups:
+  - name: PROXY
+    type: select
+    proxies: []
+  - name: Streaming
+    type: select
+    proxies:
+      - PROXY
+      - DIRECT
+rules:
+  - DOMAIN-SUFFIX,netflix.com,Streaming
+  - DOMAIN-SUFFIX,youtube.com,Streaming
+  - DOMAIN-SUFFIX,cn,DIRECT
+  - GEOIP,CN,DIRECT
+  - MATCH,PROXY
+"#;
+
+// A share link like the ones subscription providers hand out, varied by index so
+// names, servers and credentials are all distinct
+fn link(scheme: &str, i: usize) -> String {
+    let region = REGIONS[i % REGIONS.len()];
+    let name = format!("{} {:02} | IPLC", region, i);
+    let name = percent_encoding::utf8_percent_encode(&name, percent_encoding::NON_ALPHANUMERIC);
+    let host = format!("node{}.example-edge.net", i);
+    let uuid = format!("2f0c6a3e-8b1d-4c5e-9f7a-{:012x}", i);
+    match scheme {
+        "vless" => format!(
+            "vless://{uuid}@{host}:443?encryption=none&flow=xtls-rprx-vision&security=reality\
+             &sni=www.microsoft.com&fp=chrome&pbk=SbVKOEMjK0sIlbwg4akyBg5mL5KZwwB-ed4eEE7YnRc\
+             &sid=6ba85179e30d4fc2&type=tcp&headerType=none#{name}"
+        ),
+        "vmess" => {
+            let json = format!(
+                r#"{{"v":"2","ps":"{region} {i:02} | IPLC","add":"{host}","port":"8443","id":"{uuid}","aid":"0","scy":"auto","net":"ws","type":"none","host":"{host}","path":"/ray?ed=2048","tls":"tls","sni":"{host}","alpn":"h2,http/1.1","fp":"chrome"}}"#
+            );
+            format!("vmess://{}", general_purpose::STANDARD.encode(json))
+        }
+        "hysteria2" => format!(
+            "hysteria2://{uuid}@{host}:443/?sni={host}&obfs=salamander&obfs-password=cry_me_a_r1ver\
+             &mport=20000-50000&insecure=0#{name}"
+        ),
+        "trojan" => format!(
+            "trojan://{uuid}@{host}:443?security=tls&sni={host}&type=ws&host={host}&path=%2Ftrojan-ws\
+             &alpn=h2%2Chttp%2F1.1&fp=firefox#{name}"
+        ),
+        "ss" => {
+            let userinfo = general_purpose::URL_SAFE_NO_PAD.encode(format!("2022-blake3-aes-128-gcm:{}", uuid));
+            format!("ss://{userinfo}@{host}:8388?plugin=obfs-local%3Bobfs%3Dtls%3Bobfs-host%3Dbing.com#{name}")
+        }
+        "tuic" => format!(
+            "tuic://{uuid}:{i}pass@{host}:443?congestion_control=bbr&alpn=h3&sni={host}\
+             &udp_relay_mode=native&heartbeat_interval=10s#{name}"
+        ),
+        _ => unreachable!(),
+    }
+}
+
+const SCHEMES: &[&str] = &["vless", "vmess", "hysteria2", "trojan", "ss", "tuic"];
+
+// `count` links cycling through every protocol
+fn links(count: usize) -> Vec<String> {
+    (0..count).map(|i| link(SCHEMES[i % SCHEMES.len()], i)).collect()
+}
+
+fn bench_parse_link(c: &mut Criterion) {
+    let mut group = c.benchmark_group("parse_link");
+    for scheme in SCHEMES {
+        let link = link(scheme, 7);
+        assert!(parse_link(&link).is_ok(), "benchmark {} link doesn't parse", scheme);
+        group.bench_with_input(BenchmarkId::from_parameter(scheme), &link, |b, link| {
+            b.iter(|| parse_link(black_box(link)))
+        });
+    }
+    group.finish();
+}
+
+fn bench_generate(c: &mut Criterion) {
+    let options = ClashGenOptions { emoji: true, ..Default::default() };
+    let mut group = c.benchmark_group("generate_clash_yaml");
+    group.sample_size(30);
+    for count in [100, 500, 1000] {
+        let input = links(count);
+        group.bench_with_input(BenchmarkId::new("default", count), &input, |b, input| {
+            b.iter(|| generate_clash_yaml(input.clone(), ProxyList::default(), None, &options).unwrap())
+        });
+        group.bench_with_input(BenchmarkId::new("template", count), &input, |b, input| {
+            b.iter(|| {
+                generate_clash_yaml(input.clone(), ProxyList::default(), Some(TEMPLATE.to_string()), &options)
+                    .unwrap()
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_parse_link, bench_generate);
+criterion_main!(benches);
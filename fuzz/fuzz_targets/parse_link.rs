@@ -0,0 +1,14 @@
+#![no_main]
+
+// Any input may be rejected, but none may panic. Links that parse are also turned back
+// into links and re-parsed, so `to_link` is exercised with the same inputs.
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &str| {
+    if let Ok(proxy) = txt2sub::parse_link(data) {
+        let _ = txt2sub::parse_link(&proxy.to_link());
+    }
+    if let Ok(proxy) = txt2sub::parse_wireguard(data) {
+        let _ = txt2sub::parse_link(&proxy.to_link());
+    }
+});
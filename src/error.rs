@@ -0,0 +1,111 @@
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum Txt2SubError {
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    // The template isn't valid YAML
+    #[error("Failed to parse template: {0}")]
+    TemplateParse(#[source] serde_yaml::Error),
+    // Turning the generated config into YAML failed
+    #[error("Failed to serialize config: {0}")]
+    Serialize(#[from] serde_yaml::Error),
+    #[error("JSON error: {0}")]
+    JsonParse(#[from] serde_json::Error),
+    // The template parsed but doesn't have the shape of a Clash config
+    #[error("Invalid template: {0}")]
+    TemplateInvalid(String),
+    // Neither a links file nor a WireGuard config was given
+    #[error("You must provide --file, --wireguard or --import.")]
+    NoProxySources,
+    // There were no links or extra proxies to generate from
+    #[error("No links or proxies to generate a config from")]
+    EmptyInput,
+    // Links were given, but every one failed to parse or was filtered out
+    #[error("No proxies to generate a config from: every link failed to parse or was filtered out")]
+    NoProxiesParsed,
+    #[error("Invalid link: {0}")]
+    InvalidLink(#[from] ParseError),
+    #[error("Invalid WireGuard config: {0}")]
+    InvalidWireGuard(String),
+    // The config given to --import isn't a Clash config with a `proxies` list
+    #[error("Invalid Clash config to import: {0}")]
+    InvalidImport(String),
+}
+
+impl Txt2SubError {
+    // Errors caused by the user's template rather than the links or the generator
+    pub fn is_template_error(&self) -> bool {
+        matches!(self, Txt2SubError::TemplateParse(_) | Txt2SubError::TemplateInvalid(_))
+    }
+}
+
+// A share link that couldn't be turned into a proxy, tagged with its protocol.
+// Each variant holds the offending link and, once the scheme is known, why it was rejected.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum ParseError {
+    #[error("malformed vless link ({reason}): {link}")]
+    Vless { link: String, reason: &'static str },
+    #[error("malformed vmess link ({reason}): {link}")]
+    Vmess { link: String, reason: &'static str },
+    #[error("malformed hysteria2 link ({reason}): {link}")]
+    Hy2 { link: String, reason: &'static str },
+    #[error("malformed trojan link ({reason}): {link}")]
+    Trojan { link: String, reason: &'static str },
+    #[error("malformed ss link ({reason}): {link}")]
+    Ss { link: String, reason: &'static str },
+    #[error("malformed tuic link ({reason}): {link}")]
+    Tuic { link: String, reason: &'static str },
+    // The scheme isn't one txt2sub understands
+    #[error("unsupported scheme: {0}")]
+    Unknown(String),
+}
+
+impl ParseError {
+    pub fn link(&self) -> &str {
+        match self {
+            ParseError::Vless { link, .. }
+            | ParseError::Vmess { link, .. }
+            | ParseError::Hy2 { link, .. }
+            | ParseError::Trojan { link, .. }
+            | ParseError::Ss { link, .. }
+            | ParseError::Tuic { link, .. }
+            | ParseError::Unknown(link) => link,
+        }
+    }
+
+    // Why the link was rejected, without the link itself
+    pub fn reason(&self) -> &'static str {
+        match self {
+            ParseError::Vless { reason, .. }
+            | ParseError::Vmess { reason, .. }
+            | ParseError::Hy2 { reason, .. }
+            | ParseError::Trojan { reason, .. }
+            | ParseError::Ss { reason, .. }
+            | ParseError::Tuic { reason, .. } => reason,
+            ParseError::Unknown(_) => "unsupported scheme",
+        }
+    }
+}
+
+// Why a template can't be used as a Clash config, reported at startup
+#[derive(Debug, Error)]
+pub enum TemplateError {
+    #[error("invalid YAML: {0}")]
+    Yaml(#[from] serde_yaml::Error),
+    #[error("the template must be a YAML mapping")]
+    NotMapping,
+    // `proxies` is present but neither a list nor null
+    #[error("`proxies` must be a list")]
+    ProxiesNotList,
+    #[error("`proxy-groups` must be a list")]
+    ProxyGroupsNotList,
+    // The proxy group at this index isn't a mapping with a `name`
+    #[error("`proxy-groups` entry {} must be a mapping with a `name`", .0 + 1)]
+    ProxyGroupUnnamed(usize),
+    #[error("`rules` must be a list")]
+    RulesNotList,
+    // The rule at this index isn't a string
+    #[error("`rules` entry {} must be a string", .0 + 1)]
+    RuleNotString(usize),
+}
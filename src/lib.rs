@@ -0,0 +1,11 @@
+//! Turns proxy share links (vless, vmess, hysteria2, trojan, ss, tuic) and WireGuard
+//! configs into Clash configs. The `txt2sub` binary serves them as subscriptions.
+
+pub mod clash_generator;
+pub mod error;
+
+pub use clash_generator::{
+    generate_clash_yaml, generate_clash_yaml_with_warnings, import_clash_proxies, parse_link, parse_wireguard, ClashConfigBuilder,
+    ClashGenOptions, Proxy, ProxyList, ProxyType,
+};
+pub use error::{ParseError, TemplateError, Txt2SubError};
@@ -1,236 +1,2484 @@
 use axum::{
     extract::{Query, State},
     http::{header, HeaderMap, HeaderValue, StatusCode},
-    response::IntoResponse,
+    response::{Html, IntoResponse},
     routing::get,
     Router,
 };
-use clap::Parser;
-use std::{collections::HashMap, net::SocketAddr, path::PathBuf, sync::Arc};
-use tokio::fs;
+use clap::{Parser, ValueEnum};
+use std::{
+    collections::HashMap,
+    hash::{DefaultHasher, Hash, Hasher},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
+};
+use tokio::{fs, io::AsyncReadExt};
+use tower_http::{cors::CorsLayer, limit::RequestBodyLimitLayer, timeout::TimeoutLayer};
+use tracing::{error, info, warn};
 use uuid::Uuid;
 use base64::{Engine as _, engine::general_purpose};
 
-mod clash_generator;
+use txt2sub::{clash_generator, error};
+
+// Every endpoint is a GET, so request bodies only need room for stray clients
+const MAX_REQUEST_BODY: usize = 64 * 1024;
+
+// Landing page at `/` showing the subscription URL and its QR code for the token in its query
+const INDEX_HTML: &str = include_str!("index.html");
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
-    /// Path to the text file containing subscription links
-    #[arg(short, long)]
-    file: Option<PathBuf>,
+    /// Path to the text file containing subscription links (`-` reads from stdin). Repeat to
+    /// concatenate several files
+    #[arg(short, long, env = "TXT2SUB_FILE")]
+    file: Vec<PathBuf>,
+
+    /// YAML file with generation settings (rename rules, filters, sort, groups). Flags given on the
+    /// command line take precedence over it
+    #[arg(long, value_name = "PATH", env = "TXT2SUB_CONFIG")]
+    config: Option<PathBuf>,
 
-    /// Path to the WireGuard configuration file
-    #[arg(short, long)]
-    wireguard: Option<PathBuf>,
+    /// Path to the WireGuard configuration file. Repeat to add several WireGuard proxies, each
+    /// named after its file stem
+    #[arg(short, long, env = "TXT2SUB_WIREGUARD")]
+    wireguard: Vec<PathBuf>,
+
+    /// Path to an existing Clash config whose `proxies` are imported and re-exported
+    #[arg(long, env = "TXT2SUB_IMPORT")]
+    import: Option<PathBuf>,
 
     /// Port to listen on
-    #[arg(short, long, default_value_t = 3000)]
+    #[arg(short, long, default_value_t = 3000, env = "TXT2SUB_PORT")]
     port: u16,
 
-    /// Host to listen on
-    #[arg(short = 'L', long, default_value = "0.0.0.0")]
-    host: String,
+    /// Addresses to listen on, comma-separated or repeated (e.g. "0.0.0.0,::" for IPv4 and IPv6)
+    #[arg(short = 'L', long, default_value = "0.0.0.0", value_delimiter = ',', env = "TXT2SUB_HOST")]
+    host: Vec<std::net::IpAddr>,
+
+    /// Listen on a Unix domain socket at this path instead of TCP (--host/--port are ignored)
+    #[cfg(unix)]
+    #[arg(long, env = "TXT2SUB_UNIX_SOCKET")]
+    unix_socket: Option<PathBuf>,
+
+    /// Permissions of the --unix-socket file, in octal
+    #[cfg(unix)]
+    #[arg(long, default_value = "600", value_parser = parse_socket_mode, env = "TXT2SUB_UNIX_SOCKET_MODE")]
+    unix_socket_mode: u32,
+
+    /// Path of the subscription endpoint, e.g. "/s/3f9a" to keep it away from scanners
+    #[arg(long, default_value = "/sub", value_parser = parse_sub_path, env = "TXT2SUB_SUB_PATH")]
+    sub_path: String,
+
+    /// URL prefix to serve every endpoint under, e.g. "/txt2sub" for /txt2sub/sub
+    #[arg(long, default_value = "", value_parser = parse_base_path, env = "TXT2SUB_BASE_PATH")]
+    base_path: String,
 
     /// Custom UUID for the subscription URL. If not provided, a random one will be generated.
-    #[arg(short, long)]
+    #[arg(short, long, env = "TXT2SUB_UUID")]
     uuid: Option<String>,
 
-    /// Path to the Clash config template (optional)
-    #[arg(short, long)]
-    template: Option<PathBuf>,
+    /// Path to the Clash config template (optional). Repeat as `--template name=path` to add
+    /// named templates selectable with `?template=name`
+    #[arg(short, long, env = "TXT2SUB_TEMPLATE")]
+    template: Vec<TemplateArg>,
+
+    /// URL of the Clash config template, fetched and checked at startup. With --watch it is
+    /// fetched again every few minutes
+    #[arg(long, conflicts_with = "template", env = "TXT2SUB_TEMPLATE_URL")]
+    template_url: Option<url::Url>,
 
     /// Path to output the generated Clash config file. If specified, the server will not start.
-    #[arg(short, long)]
+    #[arg(short, long, env = "TXT2SUB_OUTPUT")]
     output: Option<PathBuf>,
+
+    /// String prepended to every proxy name (e.g. "Provider1 | ")
+    #[arg(long, allow_hyphen_values = true, env = "TXT2SUB_NAME_PREFIX")]
+    name_prefix: Option<String>,
+
+    /// String appended to every proxy name (e.g. " [Beta]")
+    #[arg(long, allow_hyphen_values = true, env = "TXT2SUB_NAME_SUFFIX")]
+    name_suffix: Option<String>,
+
+    /// Cut proxy names longer than this many characters short, ending them with "…".
+    /// The prefix, suffix and flag are not counted
+    #[arg(long, env = "TXT2SUB_MAX_NAME_LEN")]
+    max_name_len: Option<usize>,
+
+    /// Rewrite proxy names with a regex, e.g. "倍率x2=>" or "香港=>HK". Repeat to apply several
+    /// rules in order; the replacement may use `$1` for capture groups
+    #[arg(long, value_name = "PATTERN=>REPLACEMENT", env = "TXT2SUB_RENAME")]
+    rename: Vec<clash_generator::RenameRule>,
+
+    /// Only keep proxies whose names match this regex
+    #[arg(long, value_name = "REGEX", env = "TXT2SUB_INCLUDE")]
+    include: Option<regex::Regex>,
+
+    /// Drop proxies whose names match this regex
+    #[arg(long, value_name = "REGEX", env = "TXT2SUB_EXCLUDE")]
+    exclude: Option<regex::Regex>,
+
+    /// Disable UDP on all generated proxies
+    #[arg(long, env = "TXT2SUB_NO_UDP")]
+    no_udp: bool,
+
+    /// Output format for --output [default: clash]. In server mode, the format served when the client is not detected as Clash [default: base64]
+    #[arg(long, value_enum, env = "TXT2SUB_FORMAT")]
+    format: Option<OutputFormat>,
+
+    /// Break base64 output into lines of this many characters, for clients that expect MIME-style
+    /// base64 [default when given without a width: 76]
+    #[arg(long, value_name = "WIDTH", num_args = 0..=1, default_missing_value = "76", env = "TXT2SUB_BASE64_WRAP")]
+    base64_wrap: Option<usize>,
+
+    /// Seconds clients should wait between subscription refreshes, sent as is in the
+    /// `Profile-Update-Interval` header
+    #[arg(long, value_name = "SECONDS", default_value_t = 86400, env = "TXT2SUB_UPDATE_INTERVAL")]
+    update_interval: u64,
+
+    /// Only parse links with these schemes (e.g. "ss,trojan"); other links are skipped
+    #[arg(long, value_delimiter = ',', env = "TXT2SUB_ALLOW_SCHEMES")]
+    allow_schemes: Option<Vec<String>>,
+
+    /// Sort proxies by "name", "type" (then name) or "server"
+    #[arg(long, env = "TXT2SUB_SORT")]
+    sort: Option<clash_generator::SortKey>,
+
+    /// Clash core to generate for: "clash" (original, drops Meta-only proxies and fields) or "meta" [default: meta].
+    /// In server mode this overrides User-Agent detection.
+    #[arg(long, env = "TXT2SUB_TARGET")]
+    target: Option<clash_generator::ClashTarget>,
+
+    /// Pass link parameters the parser doesn't recognize (e.g. `ech`) through into the Clash proxies
+    #[arg(long, env = "TXT2SUB_PASSTHROUGH_UNKNOWN")]
+    passthrough_unknown: bool,
+
+    /// Parse every link and report failures without starting the server or writing output.
+    /// The exit code is the number of failed links (capped at 255).
+    #[arg(long, visible_alias = "validate", env = "TXT2SUB_CHECK")]
+    check: bool,
+
+    /// Print the --check report as a JSON object instead of text
+    #[arg(long, requires = "check", env = "TXT2SUB_JSON")]
+    json: bool,
+
+    /// Set `tls: true` on proxies on these ports whose link doesn't say whether TLS is used (e.g. "443,8443")
+    #[arg(long, value_delimiter = ',', env = "TXT2SUB_ASSUME_TLS_ON")]
+    assume_tls_on: Vec<u16>,
+
+    /// Seconds a generated response is served from memory before it is regenerated (0 disables the cache).
+    /// SIGHUP clears the cache.
+    #[arg(long, default_value_t = 0, env = "TXT2SUB_CACHE_TTL")]
+    cache_ttl: u64,
+
+    /// Watch the links, WireGuard, import and template files and clear the --cache-ttl cache when
+    /// they change
+    #[arg(long, env = "TXT2SUB_WATCH")]
+    watch: bool,
+
+    /// Seconds a request may take before the server answers 504 Gateway Timeout
+    #[arg(long, default_value_t = 30, env = "TXT2SUB_REQUEST_TIMEOUT")]
+    request_timeout: u64,
+
+    /// Allow browsers on this origin (e.g. "https://dash.example.com") to fetch the endpoints.
+    /// Repeatable; "*" allows any origin. Without it no CORS headers are sent
+    #[arg(long, value_name = "ORIGIN", value_parser = parse_cors_origin, env = "TXT2SUB_CORS_ORIGIN")]
+    cors_origin: Vec<HeaderValue>,
+
+    /// "text" prints messages as plain lines; "json" prints one JSON object per event to stdout,
+    /// including one per request, for log collectors
+    #[arg(long, value_enum, default_value = "text", env = "TXT2SUB_LOG_FORMAT")]
+    log_format: LogFormat,
+
+    /// Append a line per request to this file in the Apache combined log format (`-` for stdout).
+    /// The file is opened in append mode, so logrotate can rotate it
+    #[arg(long, value_name = "PATH", env = "TXT2SUB_ACCESS_LOG")]
+    access_log: Option<PathBuf>,
+
+    /// Refuse to read a links file larger than this many bytes
+    #[arg(long, default_value_t = 16 * 1024 * 1024, env = "TXT2SUB_MAX_FILE_SIZE")]
+    max_file_size: u64,
+
+    /// Ignore links beyond this many, with a warning
+    #[arg(long, default_value_t = 10_000, env = "TXT2SUB_MAX_NODES")]
+    max_nodes: usize,
+
+    /// Move proxies whose name contains this string to the front of the list and groups (repeatable)
+    #[arg(long, env = "TXT2SUB_PIN")]
+    pin: Vec<String>,
+
+    /// Icon URL for the default "Proxy" group, shown by mihomo dashboards
+    #[arg(long, env = "TXT2SUB_PROXY_ICON")]
+    proxy_icon: Option<String>,
+
+    /// Icon URL for the default "Auto" group, shown by mihomo dashboards
+    #[arg(long, env = "TXT2SUB_AUTO_ICON")]
+    auto_icon: Option<String>,
+
+    /// Name of the default select group [default: Proxy]
+    #[arg(long, value_name = "NAME", env = "TXT2SUB_PROXY_GROUP_NAME")]
+    proxy_group_name: Option<String>,
+
+    /// Name of the default url-test group [default: Auto]
+    #[arg(long, value_name = "NAME", env = "TXT2SUB_AUTO_GROUP_NAME")]
+    auto_group_name: Option<String>,
+
+    /// Rules of the default config: "global" sends everything through the select group,
+    /// "bypass-cn" sends local networks and mainland China direct [default: global]
+    #[arg(long, value_name = "PRESET", env = "TXT2SUB_RULES_PRESET")]
+    rules_preset: Option<clash_generator::RulesPreset>,
+
+    /// Add a select group with this name and `include-all: true` to the default config, so mihomo
+    /// fills it with every node including proxy-provider ones
+    #[arg(long, env = "TXT2SUB_INCLUDE_ALL_GROUP")]
+    include_all_group: Option<String>,
+
+    /// Only add proxies whose names match this regex to the default "Auto" url-test group.
+    /// The "Proxy" select group still lists every proxy. With --provider-mode it becomes the
+    /// group's mihomo `filter`.
+    #[arg(long, value_name = "REGEX", env = "TXT2SUB_GROUP_FILTER")]
+    group_filter: Option<regex::Regex>,
+
+    /// Prepend a flag emoji to proxy names whose region is recognized (e.g. "HK", "香港", "Japan").
+    /// `--emoji=false` turns off an `emoji: true` from --config
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true", env = "TXT2SUB_EMOJI")]
+    emoji: Option<bool>,
+
+    /// Add a select group per detected region to the default config, plus "Others" for the rest.
+    /// `--region-groups=false` turns off a `region-groups: true` from --config
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true", env = "TXT2SUB_REGION_GROUPS")]
+    region_groups: Option<bool>,
+
+    /// Add a `dns` block (fake-ip mode with common DoH resolvers) to the default config
+    #[arg(long, env = "TXT2SUB_WITH_DNS")]
+    with_dns: bool,
+
+    /// Add the top-level `mixed-port`, `allow-lan`, `mode: rule` and `log-level: info` settings
+    /// to the default config
+    #[arg(long, env = "TXT2SUB_WITH_DEFAULTS")]
+    with_defaults: bool,
+
+    /// `mixed-port` written to the default config [default: 7890]. Implies --with-defaults
+    #[arg(long, env = "TXT2SUB_MIXED_PORT")]
+    mixed_port: Option<u16>,
+
+    /// Write `allow-lan: true` to the default config. Implies --with-defaults
+    #[arg(long, env = "TXT2SUB_ALLOW_LAN")]
+    allow_lan: bool,
+
+    /// Drop duplicate proxies (same server, port and protocol), keeping the first occurrence.
+    /// `--dedup=false` turns off a `dedup: true` from --config
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true", env = "TXT2SUB_DEDUP")]
+    dedup: Option<bool>,
+
+    /// Serve Clash configs that pull their nodes from this server's /provider endpoint
+    /// as a proxy provider instead of inlining them
+    #[arg(long, env = "TXT2SUB_PROVIDER_MODE")]
+    provider_mode: bool,
+
+    /// Serve /debug?token=, listing the parsed proxies and parse failures as JSON
+    #[arg(long, env = "TXT2SUB_ENABLE_DEBUG")]
+    enable_debug: bool,
+}
+
+// `--template path` sets the default template, `--template name=path` a named one
+#[derive(Debug, Clone)]
+struct TemplateArg {
+    name: Option<String>,
+    path: PathBuf,
+}
+
+// "600", "0600" and "0o600" are all rw for the owner only
+#[cfg(unix)]
+fn parse_socket_mode(s: &str) -> Result<u32, String> {
+    let digits = s.strip_prefix("0o").unwrap_or(s);
+    match u32::from_str_radix(digits, 8) {
+        Ok(mode) if mode <= 0o777 => Ok(mode),
+        _ => Err(format!("{:?} is not an octal file mode like 0600", s)),
+    }
+}
+
+// The subscription path must be absolute and must not shadow another endpoint. It is also
+// put into the landing page's script, so it's limited to plain path characters.
+fn parse_sub_path(s: &str) -> Result<String, String> {
+    if !s.starts_with('/') {
+        return Err("the subscription path must start with /".to_string());
+    }
+    let path = s.trim_end_matches('/');
+    if path.is_empty() || ["/qr", "/provider", "/debug"].contains(&path) {
+        return Err(format!("{:?} is taken by another endpoint", s));
+    }
+    if !path.chars().all(|c| c.is_ascii_alphanumeric() || "/-_.~".contains(c)) {
+        return Err("the subscription path may only contain letters, digits and / - _ . ~".to_string());
+    }
+    Ok(path.to_string())
+}
+
+// "txt2sub", "/txt2sub" and "/txt2sub/" all mount under /txt2sub; "" and "/" keep the root
+fn parse_base_path(s: &str) -> Result<String, String> {
+    let path = s.trim_matches('/');
+    if path.contains(['?', '#', '{', '}']) {
+        return Err("the base path must be a plain URL path".to_string());
+    }
+    Ok(if path.is_empty() { String::new() } else { format!("/{}", path) })
+}
+
+impl FromStr for TemplateArg {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some((name, path)) = s.split_once('=')
+            && !name.is_empty()
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            return Ok(TemplateArg { name: Some(name.to_string()), path: PathBuf::from(path) });
+        }
+        Ok(TemplateArg { name: None, path: PathBuf::from(s) })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum OutputFormat {
+    Clash,
+    Base64,
+    Raw,
+}
+
+// Settings read from --config. Keys are the flag names, and each one is only used when
+// the flag isn't given.
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+struct ConfigFile {
+    name_prefix: Option<String>,
+    name_suffix: Option<String>,
+    max_name_len: Option<usize>,
+    #[serde(deserialize_with = "parse_each")]
+    rename: Vec<clash_generator::RenameRule>,
+    #[serde(deserialize_with = "parse_optional")]
+    include: Option<regex::Regex>,
+    #[serde(deserialize_with = "parse_optional")]
+    exclude: Option<regex::Regex>,
+    #[serde(deserialize_with = "parse_optional")]
+    sort: Option<clash_generator::SortKey>,
+    dedup: Option<bool>,
+    pin: Vec<String>,
+    emoji: Option<bool>,
+    region_groups: Option<bool>,
+    #[serde(deserialize_with = "parse_optional")]
+    group_filter: Option<regex::Regex>,
+    include_all_group: Option<String>,
+    proxy_icon: Option<String>,
+    auto_icon: Option<String>,
+    proxy_group_name: Option<String>,
+    auto_group_name: Option<String>,
+    #[serde(deserialize_with = "parse_optional")]
+    rules_preset: Option<clash_generator::RulesPreset>,
+}
+
+impl ConfigFile {
+    fn load(path: &Path) -> anyhow::Result<Self> {
+        let content = std::fs::read_to_string(path)?;
+        // An empty file is a config with nothing set
+        if content.trim().is_empty() {
+            return Ok(ConfigFile::default());
+        }
+        Ok(serde_yaml::from_str(&content)?)
+    }
+}
+
+fn parse_cors_origin(s: &str) -> Result<HeaderValue, String> {
+    if s != "*" && !(s.starts_with("http://") || s.starts_with("https://")) {
+        return Err("expected an origin like https://dash.example.com, or *".to_string());
+    }
+    HeaderValue::from_str(s.trim_end_matches('/')).map_err(|e| e.to_string())
+}
+
+// Lets the listed origins read the responses, including the node-count headers
+fn cors_layer(origins: &[HeaderValue]) -> CorsLayer {
+    use tower_http::cors::{AllowOrigin, Any};
+
+    let origin = if origins.iter().any(|o| o == "*") { AllowOrigin::from(Any) } else { AllowOrigin::list(origins.to_vec()) };
+    CorsLayer::new()
+        .allow_origin(origin)
+        .allow_methods([axum::http::Method::GET])
+        .expose_headers([
+            header::HeaderName::from_static("profile-update-interval"),
+            header::HeaderName::from_static("x-node-count"),
+            header::HeaderName::from_static("x-proxy-count"),
+            header::HeaderName::from_static("x-parse-warnings"),
+            header::HeaderName::from_static("x-upstream-error"),
+        ])
+}
+
+// Generation settings from the flags, falling back to the config file for those not given
+fn gen_options(args: &Args, config: ConfigFile) -> clash_generator::ClashGenOptions {
+    let allow_schemes = args.allow_schemes.as_ref().map(|schemes| {
+        schemes.iter().map(|s| s.trim().to_lowercase()).filter(|s| !s.is_empty()).collect()
+    });
+
+    clash_generator::ClashGenOptions {
+        name_prefix: args.name_prefix.clone().or(config.name_prefix),
+        name_suffix: args.name_suffix.clone().or(config.name_suffix),
+        udp: if args.no_udp { Some(false) } else { None },
+        types: None,
+        allowed_schemes: allow_schemes,
+        sort: args.sort.or(config.sort),
+        target: args.target.unwrap_or_default(),
+        passthrough_unknown: args.passthrough_unknown,
+        limit: None,
+        dedup: args.dedup.or(config.dedup).unwrap_or(false).then_some(clash_generator::DedupKey::Server),
+        assume_tls_ports: args.assume_tls_on.clone(),
+        pin: flag_or_config(&args.pin, config.pin),
+        proxy_icon: args.proxy_icon.clone().or(config.proxy_icon),
+        auto_icon: args.auto_icon.clone().or(config.auto_icon),
+        include_all_group: args.include_all_group.clone().or(config.include_all_group),
+        group_filter: args.group_filter.clone().or(config.group_filter),
+        emoji: args.emoji.or(config.emoji).unwrap_or(false),
+        region_groups: args.region_groups.or(config.region_groups).unwrap_or(false),
+        with_dns: args.with_dns,
+        with_defaults: args.with_defaults || args.mixed_port.is_some() || args.allow_lan,
+        mixed_port: args.mixed_port.unwrap_or(clash_generator::DEFAULT_MIXED_PORT),
+        allow_lan: args.allow_lan,
+        max_name_len: args.max_name_len.or(config.max_name_len),
+        rename: flag_or_config(&args.rename, config.rename),
+        include: args.include.clone().or(config.include),
+        exclude: args.exclude.clone().or(config.exclude),
+        proxy_group_name: args.proxy_group_name.clone().or(config.proxy_group_name),
+        auto_group_name: args.auto_group_name.clone().or(config.auto_group_name),
+        rules_preset: args.rules_preset.or(config.rules_preset).unwrap_or_default(),
+    }
+}
+
+// A repeatable flag given on the command line replaces the config file's list
+fn flag_or_config<T: Clone>(flag: &[T], config: Vec<T>) -> Vec<T> {
+    if flag.is_empty() { config } else { flag.to_vec() }
+}
+
+// Config values are written the way they are on the command line, so they go through
+// the same FromStr parsers as the flags
+fn parse_optional<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: FromStr,
+    T::Err: std::fmt::Display,
+{
+    let value: Option<String> = serde::Deserialize::deserialize(deserializer)?;
+    value.map(|v| v.parse().map_err(serde::de::Error::custom)).transpose()
+}
+
+fn parse_each<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: FromStr,
+    T::Err: std::fmt::Display,
+{
+    let values: Vec<String> = serde::Deserialize::deserialize(deserializer)?;
+    values.iter().map(|v| v.parse().map_err(serde::de::Error::custom)).collect()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum LogFormat {
+    Text,
+    Json,
 }
 
 #[derive(Clone)]
 struct AppState {
-    file_path: Option<PathBuf>,
-    wireguard_path: Option<PathBuf>,
+    file_paths: Vec<PathBuf>,
+    // Links read from stdin at startup, used in place of the `-` entry of file_paths
+    stdin_links: Option<Vec<String>>,
+    wireguard_paths: Vec<PathBuf>,
+    import_path: Option<PathBuf>,
     sub_uuid: String,
+    // --base-path, empty when serving at the root
+    base_path: String,
+    // Path of the subscription endpoint under base_path, "/sub" by default
+    sub_path: String,
     template_path: Option<PathBuf>,
+    // Named templates selectable with ?template=name
+    templates: HashMap<String, PathBuf>,
+    // Last good copy of the --template-url template, used when there is no template file
+    remote_template: Option<Arc<RwLock<String>>>,
+    gen_options: clash_generator::ClashGenOptions,
+    default_format: OutputFormat,
+    target: Option<clash_generator::ClashTarget>,
+    provider_mode: bool,
+    max_file_size: u64,
+    max_nodes: usize,
+    // Line width of base64 output when the request has no `wrap`
+    base64_wrap: Option<usize>,
+    // Sent on every subscription response as Profile-Update-Interval
+    update_interval: HeaderValue,
+    http_client: reqwest::Client,
+    cache: Arc<SubCache>,
+    // Last body fetched from each upstream subscription URL, served when a later fetch fails
+    upstream_bodies: Arc<RwLock<HashMap<String, String>>>,
+    request_timeout: Duration,
+}
+
+// Generated responses kept in memory for --cache-ttl
+struct SubCache {
+    ttl: Duration,
+    entries: RwLock<HashMap<String, CachedSub>>,
+}
+
+struct CachedSub {
+    created: Instant,
+    headers: HeaderMap,
+    body: String,
+}
+
+impl SubCache {
+    fn new(ttl: Duration) -> Self {
+        SubCache { ttl, entries: RwLock::new(HashMap::new()) }
+    }
+
+    fn get(&self, key: &str) -> Option<(HeaderMap, String)> {
+        let entries = self.entries.read().unwrap_or_else(|e| e.into_inner());
+        entries
+            .get(key)
+            .filter(|entry| entry.created.elapsed() < self.ttl)
+            .map(|entry| (entry.headers.clone(), entry.body.clone()))
+    }
+
+    fn insert(&self, key: String, headers: &HeaderMap, body: &str) {
+        if self.ttl.is_zero() {
+            return;
+        }
+        let mut entries = self.entries.write().unwrap_or_else(|e| e.into_inner());
+        // Expired entries are dropped here so the map doesn't grow with one-off queries
+        entries.retain(|_, entry| entry.created.elapsed() < self.ttl);
+        entries.insert(key, CachedSub { created: Instant::now(), headers: headers.clone(), body: body.to_string() });
+    }
+
+    fn clear(&self) {
+        self.entries.write().unwrap_or_else(|e| e.into_inner()).clear();
+    }
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let args = Args::parse();
+    init_logging(args.log_format);
+
+    let config = match &args.config {
+        Some(path) => ConfigFile::load(path).unwrap_or_else(|e| {
+            error!("Config file {:?} is invalid: {}", path, e);
+            std::process::exit(1);
+        }),
+        None => ConfigFile::default(),
+    };
 
     // Determine the UUID to use
-    let sub_uuid = args.uuid.unwrap_or_else(|| Uuid::new_v4().to_string());
+    let sub_uuid = args.uuid.clone().unwrap_or_else(|| Uuid::new_v4().to_string());
+
+    let gen_options = gen_options(&args, config);
 
     // Check if at least one source is provided
-    if args.file.is_none() && args.wireguard.is_none() {
-        eprintln!("Error: You must provide either --file or --wireguard.");
+    if args.file.is_empty() && args.wireguard.is_empty() && args.import.is_none() {
+        error!("{}", error::Txt2SubError::NoProxySources);
         std::process::exit(1);
     }
 
     // Check file existence
-    if let Some(path) = &args.file {
-        if !path.exists() {
-            eprintln!("Error: File {:?} does not exist.", path);
+    for path in &args.file {
+        if !is_stdin(path) && !path.exists() {
+            error!("File {:?} does not exist.", path);
             std::process::exit(1);
         }
     }
-    if let Some(path) = &args.wireguard {
+    for path in &args.wireguard {
         if !path.exists() {
-            eprintln!("Error: WireGuard file {:?} does not exist.", path);
+            error!("WireGuard file {:?} does not exist.", path);
             std::process::exit(1);
         }
     }
+    if let Some(path) = &args.import
+        && !path.exists()
+    {
+        error!("Import file {:?} does not exist.", path);
+        std::process::exit(1);
+    }
     
-    if let Some(tmpl) = &args.template {
-         if !tmpl.exists() {
-            eprintln!("Error: Template file {:?} does not exist.", tmpl);
+    for tmpl in &args.template {
+        if !tmpl.path.exists() {
+            error!("Template file {:?} does not exist.", tmpl.path);
+            std::process::exit(1);
+        }
+        let content = fs::read_to_string(&tmpl.path).await?;
+        if let Err(e) = clash_generator::validate_clash_template(&content) {
+            error!("Template file {:?} is invalid: {}", tmpl.path, e);
             std::process::exit(1);
         }
     }
 
+    let mut default_template = None;
+    let mut templates = HashMap::new();
+    for tmpl in &args.template {
+        match &tmpl.name {
+            Some(name) => {
+                templates.insert(name.clone(), tmpl.path.clone());
+            }
+            None if default_template.is_some() => {
+                error!("Only one default --template may be given; name the others with name=path.");
+                std::process::exit(1);
+            }
+            None => default_template = Some(tmpl.path.clone()),
+        }
+    }
+
+    let http_client = reqwest::Client::builder().timeout(std::time::Duration::from_secs(15)).build()?;
+    let remote_template = match &args.template_url {
+        Some(url) => match fetch_template(&http_client, url).await {
+            Ok(content) => Some(content),
+            Err(e) => {
+                error!("Failed to load the template from {}: {}", url, e);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+
+    if args.check {
+        let failed = check_sources(&args).await?;
+        // Exit code is the number of failed links, capped to fit a process status
+        std::process::exit(failed.min(255) as i32);
+    }
+
     if let Some(output_path) = args.output {
+        let format = args.format.unwrap_or(OutputFormat::Clash);
+
         let mut raw_links = Vec::new();
-        if let Some(path) = &args.file {
-            let content = fs::read_to_string(path).await?;
-            for line in content.lines() {
-                let trimmed = line.trim();
-                if trimmed.is_empty() || trimmed.starts_with('#') || trimmed.starts_with("//") {
-                    continue;
+        for path in &args.file {
+            match read_links_file(path, args.max_file_size).await {
+                Ok(content) => push_file_links(&mut raw_links, parse_link_lines(&content)),
+                Err(e) => {
+                    error!("{}", e);
+                    std::process::exit(1);
                 }
-                raw_links.push(trimmed.to_string());
             }
         }
+        let mut raw_links = cap_nodes(raw_links, args.max_nodes);
 
         let mut extra_proxies = Vec::new();
-        if let Some(path) = &args.wireguard {
+        for path in &args.wireguard {
+            let content = fs::read_to_string(path).await?;
+            match parse_wireguard_file(path, &content, args.wireguard.len() > 1) {
+                Ok(proxy) => extra_proxies.push(proxy),
+                Err(e) => warn!("Failed to parse WireGuard config from {:?}: {}", path, e),
+            }
+        }
+        if let Some(path) = &args.import {
             let content = fs::read_to_string(path).await?;
-            if let Some(proxy) = clash_generator::parse_wireguard(&content) {
-                extra_proxies.push(proxy);
-            } else {
-                 eprintln!("Warning: Failed to parse WireGuard config from {:?}", path);
+            match clash_generator::import_clash_proxies(&content) {
+                Ok(imported) => add_imported(format, imported, &mut raw_links, &mut extra_proxies),
+                Err(e) => {
+                    error!("{}", e);
+                    std::process::exit(1);
+                }
             }
         }
 
-        let template_content = if let Some(path) = &args.template {
+        let template_content = if let Some(path) = &default_template {
             Some(fs::read_to_string(path).await?)
         } else {
-            None
+            remote_template
         };
 
-        let yaml_content = clash_generator::generate_clash_yaml(raw_links, extra_proxies, template_content)?;
-        fs::write(&output_path, yaml_content).await?;
-        println!("Clash config written to {:?}", output_path);
+        let subscription =
+            render_subscription(format, raw_links, extra_proxies, template_content, &gen_options, args.base64_wrap)?;
+        fs::write(&output_path, subscription.body).await?;
+        if format == OutputFormat::Clash {
+            info!("Clash config written to {:?}", output_path);
+        } else {
+            info!("Subscription ({:?}) written to {:?}", format, output_path);
+        }
         return Ok(())
     }
 
+    // stdin can't be re-read per request, so it is ingested once at startup
+    let stdin_links = match args.file.iter().find(|path| is_stdin(path)) {
+        Some(path) => Some(parse_link_lines(&read_links_file(path, args.max_file_size).await?)),
+        None => None,
+    };
+
     let state = Arc::new(AppState {
-        file_path: args.file.clone(),
-        wireguard_path: args.wireguard.clone(),
+        file_paths: args.file.clone(),
+        stdin_links,
+        wireguard_paths: args.wireguard.clone(),
+        import_path: args.import.clone(),
         sub_uuid: sub_uuid.clone(), // Store the UUID in the app state
-        template_path: args.template.clone(),
+        base_path: args.base_path.clone(),
+        sub_path: args.sub_path.clone(),
+        template_path: default_template,
+        templates,
+        remote_template: remote_template.map(|content| Arc::new(RwLock::new(content))),
+        gen_options,
+        default_format: args.format.unwrap_or(OutputFormat::Base64),
+        target: args.target,
+        provider_mode: args.provider_mode,
+        max_file_size: args.max_file_size,
+        max_nodes: args.max_nodes,
+        base64_wrap: args.base64_wrap,
+        update_interval: HeaderValue::from(args.update_interval),
+        http_client,
+        cache: Arc::new(SubCache::new(Duration::from_secs(args.cache_ttl))),
+        upstream_bodies: Arc::new(RwLock::new(HashMap::new())),
+        request_timeout: Duration::from_secs(args.request_timeout),
     });
 
+    // A preliminary parse, so a wrong or empty source shows up in the log right away
+    match startup_node_count(&state).await {
+        Ok((count, failed)) => info!("Loaded {} nodes from the sources ({} failed to parse)", count, failed),
+        Err((_, e)) => warn!("{}", e),
+    }
+
+    #[cfg(unix)]
+    if args.cache_ttl > 0 {
+        tokio::spawn(clear_cache_on_sighup(state.clone()));
+    }
+
+    if args.watch {
+        let mut paths: Vec<PathBuf> = args.template.iter().map(|t| t.path.clone()).collect();
+        paths.extend(args.file.iter().filter(|path| !is_stdin(path)).chain(&args.wireguard).chain(&args.import).cloned());
+        watch_files(state.clone(), &paths)?;
+        if let Some(url) = args.template_url.clone() {
+            tokio::spawn(refetch_template(state.clone(), url));
+        }
+    }
+
     // Build the router with a fixed path, expecting the UUID as a query parameter
-    let app = Router::new()
-        .route("/sub", get(handle_subscription)) // Fixed path /sub
-        .with_state(state);
-
-    let host_ip: std::net::IpAddr = args.host.parse().expect("Invalid host IP address");
-    let addr = SocketAddr::from((host_ip, args.port));
-    println!("Server running on http://{}:{}/sub?token={}", args.host, args.port, sub_uuid);
-    if args.host == "0.0.0.0" {
-        println!("Subscription link: http://127.0.0.1:{}/sub?token={}", args.port, sub_uuid);
-    } else {
-        println!("Subscription link: http://{}:{}/sub?token={}", args.host, args.port, sub_uuid);
+    let app = app_router(state, args.enable_debug);
+    // Outermost, so timeouts and rejected bodies are logged with the status the client saw
+    let access_log = match &args.access_log {
+        Some(path) => Some(Arc::new(AccessLog::open(path)?)),
+        None => None,
+    };
+    let app = match access_log {
+        Some(log) => app.layer(axum::middleware::from_fn_with_state(log, log_access)),
+        None => app,
+    };
+    let app = if args.cors_origin.is_empty() { app } else { app.layer(cors_layer(&args.cors_origin)) };
+    // Text logs keep to startup and warnings; JSON logs also get an event per request
+    let app = match args.log_format {
+        LogFormat::Json => app.layer(axum::middleware::from_fn(log_request)),
+        LogFormat::Text => app,
+    };
+
+    #[cfg(unix)]
+    if let Some(socket_path) = &args.unix_socket {
+        use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+        // A socket file left behind by a previous run would make the bind fail.
+        // Anything else at that path is left alone and reported by the bind.
+        if std::fs::symlink_metadata(socket_path).is_ok_and(|m| m.file_type().is_socket()) {
+            std::fs::remove_file(socket_path)?;
+        }
+        let listener = tokio::net::UnixListener::bind(socket_path)?;
+        std::fs::set_permissions(socket_path, std::fs::Permissions::from_mode(args.unix_socket_mode))?;
+        info!(
+            "Server listening on unix socket {:?}, subscription path {}{}?token={}",
+            socket_path, args.base_path, args.sub_path, sub_uuid
+        );
+        info!("Graceful shutdown enabled: SIGINT/SIGTERM finish in-flight requests before exiting");
+        let served = axum::serve(listener, app).with_graceful_shutdown(shutdown_signal()).await;
+        // Nothing listens on the socket any more; don't leave it for the next run to trip over
+        let _ = std::fs::remove_file(socket_path);
+        served?;
+        return Ok(());
+    }
+
+    // With an IPv4 address in the list, IPv6 sockets must not also claim IPv4, or the second
+    // bind of a "0.0.0.0,::" pair fails
+    let has_ipv4 = args.host.iter().any(|ip| ip.is_ipv4());
+    let mut listeners = Vec::new();
+    for &ip in &args.host {
+        let addr = SocketAddr::from((ip, args.port));
+        match bind_tcp(addr, ip.is_ipv6() && has_ipv4) {
+            Ok(listener) => {
+                info!("Server running on http://{}{}{}?token={}", addr, args.base_path, args.sub_path, sub_uuid);
+                listeners.push((addr, listener));
+            }
+            Err(e) => warn!("Failed to listen on {}: {}", addr, e),
+        }
     }
+    let Some((first, _)) = listeners.first() else {
+        error!("Could not listen on any of the --host addresses");
+        std::process::exit(1);
+    };
+    // Wildcard addresses aren't reachable as such, so the link points at loopback instead
+    let link_addr = match first.ip() {
+        std::net::IpAddr::V4(ip) if ip.is_unspecified() => SocketAddr::from(([127, 0, 0, 1], first.port())),
+        std::net::IpAddr::V6(ip) if ip.is_unspecified() => SocketAddr::from((std::net::Ipv6Addr::LOCALHOST, first.port())),
+        _ => *first,
+    };
+    info!("Subscription link: http://{}{}{}?token={}", link_addr, args.base_path, args.sub_path, sub_uuid);
 
-    let listener = tokio::net::TcpListener::bind(addr).await?;
-    axum::serve(listener, app).await?;
+    info!("Graceful shutdown enabled: SIGINT/SIGTERM finish in-flight requests before exiting");
+
+    // One signal handler tells every listener to drain and stop
+    let (shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(());
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        let _ = shutdown_tx.send(());
+    });
+    let mut servers = tokio::task::JoinSet::new();
+    for (_, listener) in listeners {
+        let mut shutdown_rx = shutdown_rx.clone();
+        let server = axum::serve(listener, app.clone().into_make_service_with_connect_info::<SocketAddr>())
+            .with_graceful_shutdown(async move {
+                let _ = shutdown_rx.changed().await;
+            });
+        servers.spawn(server.into_future());
+    }
+    while let Some(served) = servers.join_next().await {
+        served??;
+    }
 
     Ok(())
 }
 
+// Binds like TcpListener::bind, but lets IPv6 sockets be restricted to IPv6 so they can sit
+// next to an IPv4 socket on the same port
+fn bind_tcp(addr: SocketAddr, only_v6: bool) -> std::io::Result<tokio::net::TcpListener> {
+    let socket = socket2::Socket::new(socket2::Domain::for_address(addr), socket2::Type::STREAM, None)?;
+    if only_v6 {
+        socket.set_only_v6(true)?;
+    }
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    tokio::net::TcpListener::from_std(socket.into())
+}
 
+// Fetches a --template-url template, failing on an error status or a template that
+// isn't a usable Clash config
+async fn fetch_template(client: &reqwest::Client, url: &url::Url) -> anyhow::Result<String> {
+    let content = client.get(url.clone()).send().await?.error_for_status()?.text().await?;
+    clash_generator::validate_clash_template(&content)?;
+    Ok(content)
+}
 
-async fn handle_subscription(
-    State(state): State<Arc<AppState>>,
-    headers: HeaderMap,
-    Query(params): Query<HashMap<String, String>>,
-) -> Result<impl IntoResponse, (StatusCode, String)> {
-    let token = params.get("token");
+// How often --watch fetches the --template-url template again
+const TEMPLATE_REFETCH_INTERVAL: Duration = Duration::from_secs(300);
 
-    // Check if token exists and matches the expected sub_uuid
-    if token.is_none() || token.unwrap() != &state.sub_uuid {
-        return Err((StatusCode::FORBIDDEN, "Invalid or missing token".to_string()));
+// Re-fetches the remote template and clears the cache when it changed. A failed fetch or an
+// invalid template keeps the previous copy.
+async fn refetch_template(state: Arc<AppState>, url: url::Url) {
+    let Some(remote) = state.remote_template.clone() else { return };
+    let mut interval = tokio::time::interval(TEMPLATE_REFETCH_INTERVAL);
+    // The first tick is immediate, and the template was just fetched at startup
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        match fetch_template(&state.http_client, &url).await {
+            Ok(content) => {
+                let mut current = remote.write().unwrap_or_else(|e| e.into_inner());
+                if *current != content {
+                    *current = content;
+                    state.cache.clear();
+                    info!("Template {} changed, subscription cache cleared", url);
+                }
+            }
+            Err(e) => warn!("Failed to fetch the template from {}, keeping the last copy: {}", url, e),
+        }
     }
+}
 
-    let mut raw_links = Vec::new(); 
-    let mut processed_lines = Vec::new();
+// Editors often save with several writes or a rename, so events are gathered until
+// there has been no change for this long
+const WATCH_DEBOUNCE: Duration = Duration::from_millis(500);
 
-    if let Some(path) = &state.file_path {
-        let content = fs::read_to_string(path)
-            .await
-            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to read file: {}", e)))?;
+// Clears the cache whenever one of `paths` changes. The parent directories are watched
+// rather than the files, so a file replaced by an editor's rename is still seen.
+fn watch_files(state: Arc<AppState>, paths: &[PathBuf]) -> anyhow::Result<()> {
+    use notify::Watcher;
 
-        for line in content.lines() {
-            let trimmed = line.trim();
-            if trimmed.is_empty() || trimmed.starts_with('#') || trimmed.starts_with("//") {
-                continue;
-            }
-            processed_lines.push(trimmed.to_string());
-            raw_links.push(trimmed.to_string());
+    let files: Vec<PathBuf> = paths.iter().map(std::fs::canonicalize).collect::<Result<_, _>>()?;
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    let watched = files.clone();
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        if let Ok(event) = event
+            && !event.kind.is_access()
+            && event.paths.iter().any(|p| watched.contains(p))
+        {
+            let _ = tx.send(());
         }
+    })?;
+
+    let mut dirs: Vec<&Path> = files.iter().filter_map(|f| f.parent()).collect();
+    dirs.sort();
+    dirs.dedup();
+    for dir in dirs {
+        watcher.watch(dir, notify::RecursiveMode::NonRecursive)?;
     }
+    info!("Watching {} file(s) for changes", files.len());
 
-    // Determine if Clash config is requested
-    let user_agent = headers
-        .get(header::USER_AGENT)
+    tokio::spawn(async move {
+        // Dropping the watcher would stop the events
+        let _watcher = watcher;
+        while rx.recv().await.is_some() {
+            while let Ok(Some(())) = tokio::time::timeout(WATCH_DEBOUNCE, rx.recv()).await {}
+            state.cache.clear();
+            info!("Source files changed, subscription cache cleared");
+        }
+    });
+    Ok(())
+}
+
+#[cfg(unix)]
+async fn clear_cache_on_sighup(state: Arc<AppState>) {
+    let mut hangup = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()) {
+        Ok(signal) => signal,
+        Err(e) => {
+            warn!("Failed to listen for SIGHUP: {}", e);
+            return;
+        }
+    };
+    while hangup.recv().await.is_some() {
+        state.cache.clear();
+        info!("SIGHUP received, subscription cache cleared");
+    }
+}
+
+// Text logs look the way txt2sub has always printed: plain lines on stdout, with
+// warnings and errors prefixed and sent to stderr
+fn init_logging(format: LogFormat) {
+    use tracing_subscriber::fmt::writer::MakeWriterExt;
+
+    match format {
+        LogFormat::Text => tracing_subscriber::fmt()
+            .event_format(PlainFormat)
+            .with_writer(std::io::stderr.with_max_level(tracing::Level::WARN).or_else(std::io::stdout))
+            .init(),
+        LogFormat::Json => tracing_subscriber::fmt()
+            .json()
+            .flatten_event(true)
+            .with_current_span(false)
+            .with_span_list(false)
+            .with_writer(std::io::stdout)
+            .init(),
+    }
+}
+
+struct PlainFormat;
+
+impl<S, N> tracing_subscriber::fmt::FormatEvent<S, N> for PlainFormat
+where
+    S: tracing::Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>,
+    N: for<'a> tracing_subscriber::fmt::FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &tracing_subscriber::fmt::FmtContext<'_, S, N>,
+        mut writer: tracing_subscriber::fmt::format::Writer<'_>,
+        event: &tracing::Event<'_>,
+    ) -> std::fmt::Result {
+        use tracing_subscriber::fmt::FormatFields;
+
+        match *event.metadata().level() {
+            tracing::Level::ERROR => write!(writer, "Error: ")?,
+            tracing::Level::WARN => write!(writer, "Warning: ")?,
+            _ => {}
+        }
+        ctx.format_fields(writer.by_ref(), event)?;
+        writeln!(writer)
+    }
+}
+
+// One event per request for --log-format json. The request id is only there to tell
+// apart events that would otherwise look identical.
+async fn log_request(request: axum::extract::Request, next: axum::middleware::Next) -> axum::response::Response {
+    let ip = request
+        .extensions()
+        .get::<axum::extract::ConnectInfo<SocketAddr>>()
+        .map(|info| info.0.ip().to_string());
+    let path = request.uri().path().to_string();
+    let started = Instant::now();
+
+    let response = next.run(request).await;
+
+    let proxy_count = response
+        .headers()
+        .get("x-node-count")
         .and_then(|v| v.to_str().ok())
-        .unwrap_or("")
-        .to_lowercase();
-    
-    let is_clash = user_agent.contains("clash") 
-        || user_agent.contains("mihomo") 
-        || user_agent.contains("stash")
-        || params.get("flag").map(|v| v.as_str()) == Some("clash");
-
-    if is_clash {
-        // Read template if available
-        let template_content = if let Some(path) = &state.template_path {
-            let tmpl = fs::read_to_string(path)
-                .await
-                .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to read template: {}", e)))?;
-            Some(tmpl)
-        } else {
-            None
-        };
+        .and_then(|v| v.parse::<u64>().ok());
+    info!(
+        request_id = %Uuid::new_v4(),
+        ip = ip.as_deref().unwrap_or("-"),
+        path,
+        status = response.status().as_u16(),
+        duration_ms = started.elapsed().as_millis() as u64,
+        proxy_count,
+        "request served",
+    );
+    response
+}
 
-        let mut extra_proxies = Vec::new();
-        if let Some(path) = &state.wireguard_path {
-            let content = fs::read_to_string(path).await
-                .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to read WG file: {}", e)))?;
-             if let Some(proxy) = clash_generator::parse_wireguard(&content) {
-                extra_proxies.push(proxy);
+// Where --access-log lines go
+enum AccessLog {
+    Stdout,
+    File(std::sync::Mutex<std::fs::File>),
+}
+
+impl AccessLog {
+    fn open(path: &Path) -> std::io::Result<Self> {
+        if is_stdin(path) {
+            return Ok(AccessLog::Stdout);
+        }
+        let file = std::fs::OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(AccessLog::File(std::sync::Mutex::new(file)))
+    }
+
+    fn write(&self, line: &str) {
+        use std::io::Write;
+
+        match self {
+            AccessLog::Stdout => println!("{}", line),
+            AccessLog::File(file) => {
+                // A failed write shouldn't fail the request it describes
+                if let Err(e) = writeln!(file.lock().unwrap_or_else(|e| e.into_inner()), "{}", line) {
+                    warn!("Failed to write the access log: {}", e);
+                }
+            }
+        }
+    }
+}
+
+// Logs each request in the Apache combined log format once its response is ready:
+// `IP - - [time] "GET /sub?token=*** HTTP/1.1" 200 1234 "referer" "user-agent"`.
+// The token is masked, since anyone reading the log could otherwise fetch the subscription.
+async fn log_access(
+    State(log): State<Arc<AccessLog>>,
+    request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> axum::response::Response {
+    use axum::body::HttpBody;
+
+    // Unix socket connections have no peer address
+    let ip = request
+        .extensions()
+        .get::<axum::extract::ConnectInfo<SocketAddr>>()
+        .map(|info| info.0.ip().to_string())
+        .unwrap_or_else(|| "-".to_string());
+    let referer = log_header(request.headers(), header::REFERER);
+    let user_agent = log_header(request.headers(), header::USER_AGENT);
+    let line = format!("{} {} {:?}", request.method(), masked_uri(request.uri()), request.version());
+
+    let response = next.run(request).await;
+
+    let bytes = response.body().size_hint().exact().map_or("-".to_string(), |n| n.to_string());
+    log.write(&format!(
+        "{} - - [{}] \"{}\" {} {} \"{}\" \"{}\"",
+        ip,
+        chrono::Local::now().format("%d/%b/%Y:%H:%M:%S %z"),
+        line,
+        response.status().as_u16(),
+        bytes,
+        referer,
+        user_agent,
+    ));
+    response
+}
+
+// A header value for a quoted log field, or "-" when it's missing
+fn log_header(headers: &HeaderMap, name: header::HeaderName) -> String {
+    headers.get(name).and_then(|v| v.to_str().ok()).unwrap_or("-").replace('"', "\\\"")
+}
+
+// The request path and query with the `token` value replaced by `***`
+fn masked_uri(uri: &axum::http::Uri) -> String {
+    let Some(query) = uri.query() else { return uri.path().to_string() };
+    let query: Vec<String> = query
+        .split('&')
+        .map(|pair| match pair.split_once('=') {
+            Some(("token", _)) => "token=***".to_string(),
+            _ => pair.to_string(),
+        })
+        .collect();
+    format!("{}?{}", uri.path(), query.join("&"))
+}
+
+// Resolves on Ctrl+C or SIGTERM (e.g. `docker stop`), letting axum drain active requests
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            warn!("Failed to listen for Ctrl+C: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(e) => {
+                warn!("Failed to listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
             }
         }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
 
-        // Generate Clash YAML
-        let yaml_content = clash_generator::generate_clash_yaml(raw_links, extra_proxies, template_content)
-            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to generate Clash config: {}", e)))?;
-        
-        let mut headers = HeaderMap::new();
-        headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("text/yaml; charset=utf-8"));
-        
-        return Ok((headers, yaml_content));
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
     }
+    info!("Shutting down, waiting for active requests to finish...");
+}
 
-    // Default: Base64 encode
-    // Note: If only WireGuard file is provided, processed_lines will be empty.
-    // This is expected behavior as Base64 sub usually implies a list of links.
-    let joined_content = processed_lines.join("\n");
-    let encoded = general_purpose::STANDARD.encode(joined_content);
-    
-    let mut headers = HeaderMap::new();
-    headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("text/plain; charset=utf-8"));
+// `--file -` reads the links from stdin instead of a file
+fn is_stdin(path: &Path) -> bool {
+    path.as_os_str() == "-"
+}
+
+// Reads at most `limit` bytes, so an oversized pipe is detected without buffering all of it
+async fn read_stdin(limit: u64) -> std::io::Result<Vec<u8>> {
+    let mut content = Vec::new();
+    tokio::io::stdin().take(limit).read_to_end(&mut content).await?;
+    Ok(content)
+}
+
+// One link per line with its 1-based line number; blank lines and `#`/`//` comments are skipped.
+// `# group: NAME` directives are kept so the generator can assign the links after them.
+fn link_lines(content: &str) -> impl Iterator<Item = (usize, &str)> {
+    content
+        .lines()
+        .enumerate()
+        .map(|(i, line)| (i + 1, line.trim()))
+        .filter(|(_, line)| {
+            clash_generator::group_directive(line).is_some()
+                || (!line.is_empty() && !line.starts_with('#') && !line.starts_with("//"))
+        })
+}
+
+fn parse_link_lines(content: &str) -> Vec<String> {
+    link_lines(content).map(|(_, line)| line.to_string()).collect()
+}
+
+// Appends the links of one more --file. An empty `# group:` goes between files, so a group
+// directive in one file doesn't carry over into the next.
+fn push_file_links(raw_links: &mut Vec<String>, links: impl IntoIterator<Item = String>) {
+    if !raw_links.is_empty() {
+        raw_links.push("# group:".to_string());
+    }
+    raw_links.extend(links);
+}
+
+// Reads a links file (or stdin), refusing input over `max_size` bytes and unwrapping a
+// file that holds a whole base64 subscription
+async fn read_links_file(path: &Path, max_size: u64) -> anyhow::Result<String> {
+    let content = if is_stdin(path) {
+        let content = read_stdin(max_size.saturating_add(1)).await?;
+        if content.len() as u64 > max_size {
+            anyhow::bail!("stdin is over the --max-file-size limit of {} bytes", max_size);
+        }
+        String::from_utf8(content)?
+    } else {
+        let size = fs::metadata(path).await?.len();
+        if size > max_size {
+            anyhow::bail!("{:?} is {} bytes, over the --max-file-size limit of {} bytes", path, size, max_size);
+        }
+        fs::read_to_string(path).await?
+    };
+    Ok(decode_base64_links(&content).unwrap_or(content))
+}
+
+// Every endpoint, nested under --base-path, with the request limits applied
+fn app_router(state: Arc<AppState>, enable_debug: bool) -> Router {
+    let mut routes = Router::new()
+        .route("/", get(handle_index))
+        .route("/qr", get(handle_qr))
+        .route(&state.sub_path, get(handle_subscription))
+        .route("/provider", get(handle_provider));
+    if enable_debug {
+        routes = routes.route("/debug", get(handle_debug));
+    }
+    let app = if state.base_path.is_empty() { routes } else { Router::new().nest(&state.base_path, routes) };
+    with_limits(app, state.request_timeout).with_state(state)
+}
+
+// Requests still running after `timeout` get a 504, and bodies over MAX_REQUEST_BODY a 413
+fn with_limits<S: Clone + Send + Sync + 'static>(router: Router<S>, timeout: Duration) -> Router<S> {
+    router
+        .layer(TimeoutLayer::with_status_code(StatusCode::GATEWAY_TIMEOUT, timeout))
+        .layer(RequestBodyLimitLayer::new(MAX_REQUEST_BODY))
+}
+
+// Drops links beyond the first `max_nodes`; `# group:` directives don't count
+fn cap_nodes(mut links: Vec<String>, max_nodes: usize) -> Vec<String> {
+    let is_link = |line: &String| clash_generator::group_directive(line).is_none();
+    let total = links.iter().filter(|l| is_link(l)).count();
+    if total <= max_nodes {
+        return links;
+    }
 
-    Ok((headers, encoded))
+    let mut seen = 0;
+    links.retain(|line| {
+        if !is_link(line) {
+            return true;
+        }
+        seen += 1;
+        seen <= max_nodes
+    });
+    warn!("{} links found, ignoring all but the first {} (--max-nodes)", total, max_nodes);
+    links
+}
+
+// Parses every source without serving or writing output, printing each failure, or with
+// --json a single report. Returns the number of links (and WireGuard files) that failed to parse.
+async fn check_sources(args: &Args) -> anyhow::Result<usize> {
+    let report = check_report(args).await?;
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&report.to_json())?);
+    } else {
+        for line in &report.lines {
+            println!("{}", line);
+        }
+        println!(
+            "{}/{} links parsed successfully ({} failed)",
+            report.total - report.failures.len(),
+            report.total,
+            report.failures.len()
+        );
+    }
+    Ok(report.failures.len())
+}
+
+async fn check_report(args: &Args) -> anyhow::Result<CheckReport> {
+    let mut report = CheckReport::default();
+
+    for path in &args.file {
+        let content = read_links_file(path, args.max_file_size).await?;
+        for (line_no, link) in link_lines(&content) {
+            if clash_generator::group_directive(link).is_some() {
+                continue;
+            }
+            match clash_generator::parse_link(link) {
+                Ok(proxy) => report.parsed(proxy.protocol_type().as_str()),
+                Err(e) => {
+                    let protocol = clash_generator::link_type(link).unwrap_or("unknown");
+                    // Line numbers alone are ambiguous once several files are checked
+                    let text = if args.file.len() > 1 {
+                        format!("{:?} line {}: {}", path, line_no, e)
+                    } else {
+                        format!("Line {}: {}", line_no, e)
+                    };
+                    report.failed(protocol, text, path, Some(line_no), Some(link), e.reason().to_string());
+                }
+            }
+        }
+    }
+
+    for path in &args.wireguard {
+        let content = fs::read_to_string(path).await?;
+        match clash_generator::parse_wireguard(&content) {
+            Ok(_) => report.parsed("wireguard"),
+            Err(e) => report.failed("wireguard", format!("{:?}: {}", path, e), path, None, None, e.to_string()),
+        }
+    }
+
+    if let Some(path) = &args.import {
+        let content = fs::read_to_string(path).await?;
+        match clash_generator::import_clash_proxies(&content) {
+            Ok(imported) => imported.iter().for_each(|p| report.parsed(p.protocol_type().as_str())),
+            Err(e) => report.failed("import", format!("{:?}: {}", path, e), path, None, None, e.to_string()),
+        }
+    }
+    Ok(report)
+}
+
+// What --check found: counts per protocol, the text report lines and, for --json, each failure
+#[derive(Default)]
+struct CheckReport {
+    total: usize,
+    // Protocol -> (total, failed), sorted so the JSON is stable
+    protocols: std::collections::BTreeMap<&'static str, (usize, usize)>,
+    lines: Vec<String>,
+    failures: Vec<serde_json::Value>,
+}
+
+impl CheckReport {
+    fn parsed(&mut self, protocol: &'static str) {
+        self.total += 1;
+        self.protocols.entry(protocol).or_default().0 += 1;
+    }
+
+    // `text` is the line printed for the failure without --json
+    fn failed(
+        &mut self,
+        protocol: &'static str,
+        text: String,
+        path: &Path,
+        line_number: Option<usize>,
+        content: Option<&str>,
+        reason: String,
+    ) {
+        self.parsed(protocol);
+        self.protocols.entry(protocol).or_default().1 += 1;
+        self.lines.push(text);
+        self.failures.push(serde_json::json!({
+            "file": path,
+            "line_number": line_number,
+            "content": content,
+            "protocol": protocol,
+            "reason": reason,
+        }));
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        let failed = self.failures.len();
+        let protocols: serde_json::Map<String, serde_json::Value> = self
+            .protocols
+            .iter()
+            .map(|(protocol, (total, failed))| {
+                let counts = serde_json::json!({ "total": total, "parsed": total - failed, "failed": failed });
+                (protocol.to_string(), counts)
+            })
+            .collect();
+        serde_json::json!({
+            "total": self.total,
+            "parsed": self.total - failed,
+            "failed": failed,
+            "protocols": protocols,
+            "failures": self.failures,
+        })
+    }
+}
+
+fn is_upstream_url(link: &str) -> bool {
+    link.starts_with("http://") || link.starts_with("https://")
+}
+
+// Links for one request. `upstream_error` is set when an upstream subscription couldn't be
+// fetched and there was no earlier copy of it to fall back on.
+struct LoadedLinks {
+    links: Vec<String>,
+    upstream_error: bool,
+}
+
+// Replaces http(s) subscription URLs in the link list with the links they serve.
+// Expansion is one level deep: URLs inside a fetched subscription are left as-is and
+// dropped later as unknown links. A failed fetch falls back to the last copy fetched, or is
+// logged and skipped when there is none.
+async fn expand_upstream_links(state: &AppState, links: Vec<String>, deadline: Instant) -> LoadedLinks {
+    let mut expanded = Vec::new();
+    let mut upstream_error = false;
+    for link in links {
+        if !is_upstream_url(&link) {
+            expanded.push(link);
+            continue;
+        }
+
+        match fetch_upstream(&state.http_client, &link, deadline).await {
+            Ok(body) => {
+                expanded.extend(decode_subscription(&body));
+                state.upstream_bodies.write().unwrap_or_else(|e| e.into_inner()).insert(link, body);
+            }
+            Err(e) => {
+                let previous = state.upstream_bodies.read().unwrap_or_else(|e| e.into_inner()).get(&link).cloned();
+                match previous {
+                    Some(body) => {
+                        warn!("Failed to fetch upstream subscription {}, using the last copy: {}", link, e);
+                        expanded.extend(decode_subscription(&body));
+                    }
+                    None => {
+                        warn!("Failed to fetch upstream subscription {}: {}", link, e);
+                        upstream_error = true;
+                    }
+                }
+            }
+        }
+    }
+    LoadedLinks { links: expanded, upstream_error }
+}
+
+// Waits before the second and third attempts at an upstream fetch
+const UPSTREAM_RETRY_DELAYS: [Duration; 2] = [Duration::from_secs(1), Duration::from_secs(2)];
+
+// Fetches an upstream subscription, retrying with backoff unless the retry would end
+// after `deadline` (when the request itself times out)
+async fn fetch_upstream(client: &reqwest::Client, url: &str, deadline: Instant) -> reqwest::Result<String> {
+    let mut delays = UPSTREAM_RETRY_DELAYS.iter();
+    loop {
+        let body = match client.get(url).send().await.and_then(|r| r.error_for_status()) {
+            Ok(response) => response.text().await,
+            Err(e) => Err(e),
+        };
+        match (body, delays.next()) {
+            (Ok(body), _) => return Ok(body),
+            (Err(e), Some(delay)) if Instant::now() + *delay < deadline => {
+                warn!("Fetching upstream subscription {} failed, retrying in {}s: {}", url, delay.as_secs(), e);
+                tokio::time::sleep(*delay).await;
+            }
+            (Err(e), _) => return Err(e),
+        }
+    }
+}
+
+// Subscriptions are usually base64 of newline-separated links; plain link lists are accepted too
+fn decode_subscription(body: &str) -> Vec<String> {
+    match decode_base64_links(body) {
+        Some(text) => parse_link_lines(&text),
+        None => parse_link_lines(body),
+    }
+}
+
+// A saved subscription is one base64 blob (possibly wrapped over several lines) of
+// newline-separated links. Returns the decoded text, or None when `content` isn't one.
+fn decode_base64_links(content: &str) -> Option<String> {
+    let compact: String = content.chars().filter(|c| !c.is_whitespace()).collect();
+    if compact.is_empty() {
+        return None;
+    }
+    let text = general_purpose::STANDARD
+        .decode(&compact)
+        .or_else(|_| general_purpose::STANDARD_NO_PAD.decode(&compact))
+        .or_else(|_| general_purpose::URL_SAFE.decode(&compact))
+        .or_else(|_| general_purpose::URL_SAFE_NO_PAD.decode(&compact))
+        .ok()
+        .and_then(|bytes| String::from_utf8(bytes).ok())?;
+    text.contains("://").then_some(text)
+}
+
+// Comma-separated query values, e.g. `?type=vless,vmess`
+fn split_list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(|v| v.trim().to_lowercase())
+        .filter(|v| !v.is_empty())
+        .collect()
+}
+
+struct Subscription {
+    content_type: &'static str,
+    body: String,
+    // Proxies emitted, and available before `limit` truncation
+    count: usize,
+    total: usize,
+    // Links that were skipped because they failed to parse (Clash output only)
+    warnings: Vec<String>,
+}
+
+// Collects the proxies and applies `limit`, returning them with the count before truncation
+// and a warning for every link that failed to parse (also logged to stderr)
+fn limited_proxies(
+    links: Vec<String>,
+    extra_proxies: Vec<clash_generator::Proxy>,
+    options: &clash_generator::ClashGenOptions,
+) -> (Vec<clash_generator::Proxy>, usize, Vec<String>) {
+    let mut parse_errors = Vec::new();
+    let mut proxies = clash_generator::collect_proxies(links, extra_proxies, options, &mut parse_errors);
+    let warnings: Vec<String> = parse_errors.iter().map(|e| format!("Skipping link, {}", e)).collect();
+    for warning in &warnings {
+        warn!("{}", warning);
+    }
+    let total = proxies.len();
+    if let Some(limit) = options.limit.filter(|&n| n > 0) {
+        proxies.truncate(limit);
+    }
+    (proxies, total, warnings)
+}
+
+// Renders the subscription body in the given format.
+// Shared by the `--output` path and the server so both select formats the same way.
+// Base64 output is broken into lines of `wrap` characters when it's set and not 0.
+fn render_subscription(
+    format: OutputFormat,
+    mut links: Vec<String>,
+    extra_proxies: Vec<clash_generator::Proxy>,
+    template: Option<String>,
+    options: &clash_generator::ClashGenOptions,
+    wrap: Option<usize>,
+) -> anyhow::Result<Subscription> {
+    let limit = options.limit.filter(|&n| n > 0);
+
+    if format == OutputFormat::Clash {
+        let (proxies, total, warnings) = limited_proxies(links, extra_proxies, options);
+        let count = proxies.len();
+        let yaml_content = clash_generator::render_clash_yaml(proxies, template, options)?;
+        return Ok(Subscription { content_type: "text/yaml; charset=utf-8", body: yaml_content, count, total, warnings });
+    }
+
+    // Note: If only WireGuard file is provided, links will be empty.
+    // This is expected behavior as Base64 sub usually implies a list of links.
+    links.retain(|link| {
+        clash_generator::group_directive(link).is_none() && clash_generator::link_allowed(link, options)
+    });
+    // Links pass through as they are, but only the ones that parse count as nodes, as for Clash
+    let parses: Vec<bool> = links.iter().map(|link| clash_generator::parse_link(link).is_ok()).collect();
+    let total = parses.iter().filter(|&&ok| ok).count();
+    // `limit` counts nodes, so the cut goes after the limit-th link that parses
+    if let Some(limit) = limit
+        && let Some(last) = parses.iter().enumerate().filter(|(_, ok)| **ok).nth(limit - 1).map(|(i, _)| i)
+    {
+        links.truncate(last + 1);
+    }
+    let count = parses.iter().take(links.len()).filter(|&&ok| ok).count();
+    let joined_content = links.join("\n");
+    let body = if format == OutputFormat::Base64 {
+        let encoded = general_purpose::STANDARD.encode(joined_content);
+        match wrap.filter(|&width| width > 0) {
+            Some(width) => wrap_lines(&encoded, width),
+            None => encoded,
+        }
+    } else {
+        joined_content
+    };
+
+    Ok(Subscription { content_type: "text/plain; charset=utf-8", body, count, total, warnings: Vec::new() })
+}
+
+// Base64 is ASCII, so splitting on bytes never cuts a character
+fn wrap_lines(text: &str, width: usize) -> String {
+    let lines: Vec<&str> = text
+        .as_bytes()
+        .chunks(width)
+        .map(|chunk| std::str::from_utf8(chunk).unwrap_or_default())
+        .collect();
+    lines.join("\n")
+}
+
+fn check_token(state: &AppState, params: &HashMap<String, String>) -> Result<(), (StatusCode, String)> {
+    match params.get("token") {
+        Some(token) if token == &state.sub_uuid => Ok(()),
+        _ => Err((StatusCode::FORBIDDEN, "Invalid or missing token".to_string())),
+    }
+}
+
+fn user_agent(headers: &HeaderMap) -> String {
+    headers
+        .get(header::USER_AGENT)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("")
+        .to_lowercase()
+}
+
+// Reads the links for a request, expanding upstream subscriptions with ?expand=true
+async fn load_links(state: &AppState, params: &HashMap<String, String>) -> Result<LoadedLinks, (StatusCode, String)> {
+    let deadline = Instant::now() + state.request_timeout;
+    let mut raw_links = Vec::new();
+
+    for path in &state.file_paths {
+        if let Some(lines) = state.stdin_links.as_ref().filter(|_| is_stdin(path)) {
+            push_file_links(&mut raw_links, lines.iter().cloned());
+            continue;
+        }
+        let content = read_links_file(path, state.max_file_size)
+            .await
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to read file: {}", e)))?;
+
+        push_file_links(&mut raw_links, parse_link_lines(&content));
+    }
+
+    let mut upstream_error = false;
+    if params.get("expand").map(|v| v.as_str()) == Some("true") {
+        let expanded = expand_upstream_links(state, raw_links, deadline).await;
+        raw_links = expanded.links;
+        upstream_error = expanded.upstream_error;
+    }
+    Ok(LoadedLinks { links: cap_nodes(raw_links, state.max_nodes), upstream_error })
+}
+
+async fn load_wireguard(state: &AppState) -> Result<Vec<clash_generator::Proxy>, (StatusCode, String)> {
+    let mut extra_proxies = Vec::new();
+    for path in &state.wireguard_paths {
+        let content = fs::read_to_string(path).await
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to read WG file: {}", e)))?;
+        if let Ok(proxy) = parse_wireguard_file(path, &content, state.wireguard_paths.len() > 1) {
+            extra_proxies.push(proxy);
+        }
+    }
+    Ok(extra_proxies)
+}
+
+// With several --wireguard files each proxy is named after its file stem, so the names stay
+// unique; a single file keeps the default name
+fn parse_wireguard_file(path: &Path, content: &str, name_from_stem: bool) -> Result<clash_generator::Proxy, error::Txt2SubError> {
+    let mut proxy = clash_generator::parse_wireguard(content)?;
+    if name_from_stem && let Some(stem) = path.file_stem() {
+        proxy.set_name(stem.to_string_lossy().into_owned());
+    }
+    Ok(proxy)
+}
+
+// Number of proxies the sources produce with the CLI options, and of links that failed to parse
+async fn startup_node_count(state: &AppState) -> Result<(usize, usize), (StatusCode, String)> {
+    // Upstream URLs are only fetched with ?expand=true, so they aren't counted as failures here
+    let mut links = load_links(state, &HashMap::new()).await?.links;
+    links.retain(|link| !is_upstream_url(link));
+    let mut extra_proxies = load_wireguard(state).await?;
+    extra_proxies.extend(load_imported(state).await?);
+    let mut parse_errors = Vec::new();
+    let proxies = clash_generator::collect_proxies(links, extra_proxies, &state.gen_options, &mut parse_errors);
+    Ok((proxies.len(), parse_errors.len()))
+}
+
+async fn load_imported(state: &AppState) -> Result<Vec<clash_generator::Proxy>, (StatusCode, String)> {
+    let Some(path) = &state.import_path else {
+        return Ok(Vec::new());
+    };
+    let content = fs::read_to_string(path).await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to read import file: {}", e)))?;
+    clash_generator::import_clash_proxies(&content).map_err(|e| generation_error_response(&e))
+}
+
+// Imported proxies join the Clash proxies as they are, and are turned back into
+// share links for the link-based formats
+fn add_imported(
+    format: OutputFormat,
+    imported: Vec<clash_generator::Proxy>,
+    links: &mut Vec<String>,
+    extra_proxies: &mut Vec<clash_generator::Proxy>,
+) {
+    if format == OutputFormat::Clash {
+        extra_proxies.extend(imported);
+    } else {
+        links.extend(imported.iter().map(clash_generator::Proxy::to_link));
+    }
+}
+
+// Query parameters override the CLI options for this request only
+fn request_options(
+    state: &AppState,
+    params: &HashMap<String, String>,
+    user_agent: &str,
+) -> Result<clash_generator::ClashGenOptions, (StatusCode, String)> {
+    let mut options = state.gen_options.clone();
+    if let Some(prefix) = params.get("prefix") {
+        options.name_prefix = Some(prefix.clone());
+    }
+    if let Some(suffix) = params.get("suffix") {
+        options.name_suffix = Some(suffix.clone());
+    }
+    if let Some(udp) = params.get("udp") {
+        // Overrides --no-udp either way, including WireGuard and other extra proxies
+        options.udp = match udp.as_str() {
+            "1" | "true" => Some(true),
+            "0" | "false" => Some(false),
+            other => return Err((StatusCode::BAD_REQUEST, format!("Invalid udp value: {}", other))),
+        };
+    }
+    if let Some(types) = params.get("type") {
+        options.types = Some(split_list(types));
+    }
+    if let Some(schemes) = params.get("schemes") {
+        options.allowed_schemes = Some(split_list(schemes));
+    }
+    if let Some(limit) = params.get("limit") {
+        options.limit = Some(limit.parse().map_err(|_| (StatusCode::BAD_REQUEST, format!("Invalid limit: {}", limit)))?);
+    }
+    if let Some(dedup) = params.get("dedup") {
+        options.dedup = match dedup.as_str() {
+            "true" | "1" => Some(clash_generator::DedupKey::Server),
+            "name" => Some(clash_generator::DedupKey::Name),
+            "false" | "0" => None,
+            other => return Err((StatusCode::BAD_REQUEST, format!("Invalid dedup value: {}", other))),
+        };
+    }
+    if let Some(sort) = params.get("sort") {
+        options.sort = Some(sort.parse().map_err(|e| (StatusCode::BAD_REQUEST, format!("Invalid sort: {}", e)))?);
+    }
+
+    // Stock Clash clients get a config without Meta-only proxies and fields
+    options.target = state.target.unwrap_or_else(|| clash_generator::detect_clash_target(user_agent));
+    Ok(options)
+}
+
+// Where clients reach this server, from the Host header and a reverse proxy's X-Forwarded-Proto,
+// including --base-path
+fn base_url(state: &AppState, headers: &HeaderMap) -> Result<String, (StatusCode, String)> {
+    let host = headers
+        .get(header::HOST)
+        .and_then(|v| v.to_str().ok())
+        .ok_or((StatusCode::BAD_REQUEST, "Missing Host header".to_string()))?;
+    let scheme = headers
+        .get("x-forwarded-proto")
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("http");
+    Ok(format!("{}://{}{}", scheme, host, state.base_path))
+}
+
+// URL of the /provider endpoint as seen by the client, carrying over the node-related
+// query parameters so the provider serves the same filtered list
+fn provider_url(
+    state: &AppState,
+    headers: &HeaderMap,
+    params: &HashMap<String, String>,
+) -> Result<String, (StatusCode, String)> {
+    let mut keys: Vec<&String> = params
+        .keys()
+        .filter(|k| !matches!(k.as_str(), "format" | "target" | "flag" | "template"))
+        .collect();
+    keys.sort();
+    let mut query = url::form_urlencoded::Serializer::new(String::new());
+    for key in keys {
+        query.append_pair(key, &params[key]);
+    }
+    Ok(format!("{}/provider?{}", base_url(state, headers)?, query.finish()))
+}
+
+// Serves `generate` from the cache when a fresh entry exists, otherwise runs it and caches
+// the result. Every generated response carries an ETag of its body, and a request whose
+// If-None-Match names it gets 304 Not Modified.
+async fn cached_response(
+    state: &AppState,
+    endpoint: &str,
+    headers: &HeaderMap,
+    params: &HashMap<String, String>,
+    generate: impl Future<Output = Result<(HeaderMap, String), (StatusCode, String)>>,
+) -> Result<axum::response::Response, (StatusCode, String)> {
+    let key = cache_key(endpoint, headers, params);
+    let (response_headers, body) = match state.cache.get(&key) {
+        Some(hit) => hit,
+        None => {
+            let (mut response_headers, body) = generate.await?;
+            response_headers.insert(header::ETAG, body_etag(&body));
+            // A response missing an upstream's nodes is served but not kept
+            if !response_headers.contains_key("x-upstream-error") {
+                state.cache.insert(key, &response_headers, &body);
+            }
+            (response_headers, body)
+        }
+    };
+
+    if let Some(etag) = response_headers.get(header::ETAG)
+        && etag_matches(headers, etag)
+    {
+        return Ok((StatusCode::NOT_MODIFIED, response_headers).into_response());
+    }
+    Ok((response_headers, body).into_response())
+}
+
+// The generation header's timestamp is left out, so regenerating an unchanged config
+// (after the cache expires or on another instance) keeps its ETag
+fn body_etag(body: &str) -> HeaderValue {
+    let mut hasher = DefaultHasher::new();
+    clash_generator::strip_generation_header(body).hash(&mut hasher);
+    HeaderValue::from_str(&format!("\"{:016x}\"", hasher.finish())).unwrap()
+}
+
+// If-None-Match holds `*` or a comma-separated list of tags, weak ones prefixed with `W/`
+fn etag_matches(headers: &HeaderMap, etag: &HeaderValue) -> bool {
+    let Some(wanted) = headers.get(header::IF_NONE_MATCH).and_then(|v| v.to_str().ok()) else {
+        return false;
+    };
+    let etag = etag.to_str().unwrap_or_default();
+    wanted.split(',').map(str::trim).any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+}
+
+// Everything a response depends on besides the files: the query (minus the token), the
+// User-Agent used for format and target detection, and the host used for provider URLs
+fn cache_key(endpoint: &str, headers: &HeaderMap, params: &HashMap<String, String>) -> String {
+    let mut query: Vec<_> = params.iter().filter(|(k, _)| k.as_str() != "token").collect();
+    query.sort();
+    let header = |name: &str| headers.get(name).and_then(|v| v.to_str().ok()).unwrap_or("");
+    format!(
+        "{}\n{}\n{}\n{}\n{:?}",
+        endpoint,
+        user_agent(headers),
+        header("host"),
+        header("x-forwarded-proto"),
+        query
+    )
+}
+
+// A broken template is the operator's to fix, so it gets its own message
+fn generation_error_response(e: &error::Txt2SubError) -> (StatusCode, String) {
+    if matches!(e, error::Txt2SubError::NoProxiesParsed) {
+        // The sources may recover (e.g. a links file being rewritten), so clients should retry later
+        (StatusCode::SERVICE_UNAVAILABLE, e.to_string())
+    } else if e.is_template_error() {
+        (StatusCode::INTERNAL_SERVER_ERROR, format!("Template error: {}", e))
+    } else {
+        (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to generate subscription: {}", e))
+    }
+}
+
+async fn handle_subscription(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    check_token(&state, &params)?;
+    cached_response(&state, "sub", &headers, &params, subscription_response(&state, &headers, &params)).await
+}
+
+async fn subscription_response(
+    state: &AppState,
+    headers: &HeaderMap,
+    params: &HashMap<String, String>,
+) -> Result<(HeaderMap, String), (StatusCode, String)> {
+    let user_agent = user_agent(headers);
+    let mut options = request_options(state, params, &user_agent)?;
+
+    // An explicit ?format= (or its alias ?target=) takes precedence over ?flag=clash and
+    // the User-Agent heuristic
+    let explicit = params.get("format").map(|f| ("format", f)).or_else(|| params.get("target").map(|t| ("target", t)));
+    let format = match explicit {
+        // `target=meta` (or mihomo) names the core, as --target does: Clash output for Clash.Meta
+        Some(("target", t)) if OutputFormat::from_str(t, true).is_err() && t.parse::<clash_generator::ClashTarget>().is_ok() => {
+            options.target = clash_generator::ClashTarget::Meta;
+            OutputFormat::Clash
+        }
+        Some((param, f)) => OutputFormat::from_str(f, true)
+            .map_err(|_| (StatusCode::BAD_REQUEST, format!("Unknown {}: {}", param, f)))?,
+        None => {
+            let is_clash = user_agent.contains("clash")
+                || user_agent.contains("mihomo")
+                || user_agent.contains("stash")
+                || params.get("flag").map(|v| v.as_str()) == Some("clash");
+
+            if is_clash { OutputFormat::Clash } else { state.default_format }
+        }
+    };
+
+    let mut template_content = None;
+    let mut extra_proxies = Vec::new();
+    if format == OutputFormat::Clash {
+        // Read template if available; unknown ?template= names fall back to the default
+        let template_path = params
+            .get("template")
+            .and_then(|name| state.templates.get(name))
+            .or(state.template_path.as_ref());
+        if let Some(path) = template_path {
+            let tmpl = fs::read_to_string(path)
+                .await
+                .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to read template: {}", e)))?;
+            template_content = Some(tmpl);
+        } else if let Some(remote) = &state.remote_template {
+            template_content = Some(remote.read().unwrap_or_else(|e| e.into_inner()).clone());
+        }
+
+        // The nodes are fetched separately from /provider, so no links are read here
+        if state.provider_mode {
+            let url = provider_url(state, headers, params)?;
+            let yaml_content = clash_generator::render_provider_config(&url, template_content, &options)
+                .map_err(|e| generation_error_response(&e))?;
+            let mut headers = HeaderMap::new();
+            headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("text/yaml; charset=utf-8"));
+            headers.insert("profile-update-interval", state.update_interval.clone());
+            return Ok((headers, yaml_content));
+        }
+
+        extra_proxies = load_wireguard(state).await?;
+    }
+    let wrap = match params.get("wrap") {
+        Some(width) => Some(width.parse().map_err(|_| (StatusCode::BAD_REQUEST, format!("Invalid wrap: {}", width)))?),
+        None => state.base64_wrap,
+    };
+    let LoadedLinks { links: mut raw_links, upstream_error } = load_links(state, params).await?;
+    add_imported(format, load_imported(state).await?, &mut raw_links, &mut extra_proxies);
+
+    // Parsing and rendering are CPU-bound, so they run off the async worker threads
+    let subscription = tokio::task::spawn_blocking(move || {
+        render_subscription(format, raw_links, extra_proxies, template_content, &options, wrap)
+    })
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to generate subscription: {}", e)))?
+    .map_err(|e| match e.downcast_ref::<error::Txt2SubError>() {
+        Some(e) => generation_error_response(e),
+        None => (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to generate subscription: {}", e)),
+    })?;
+
+    let mut headers = HeaderMap::new();
+    headers.insert(header::CONTENT_TYPE, HeaderValue::from_static(subscription.content_type));
+    headers.insert("profile-update-interval", state.update_interval.clone());
+    headers.insert("x-node-count", HeaderValue::from(subscription.count));
+    if subscription.count < subscription.total {
+        let proxy_count = format!("{}/{}", subscription.count, subscription.total);
+        headers.insert("x-proxy-count", HeaderValue::from_str(&proxy_count).unwrap());
+    }
+    if !subscription.warnings.is_empty() {
+        headers.insert("x-parse-warnings", HeaderValue::from(subscription.warnings.len()));
+    }
+    if upstream_error {
+        headers.insert("x-upstream-error", HeaderValue::from_static("true"));
+    }
+
+    Ok((headers, subscription.body))
+}
+
+async fn handle_index(State(state): State<Arc<AppState>>) -> Html<String> {
+    Html(INDEX_HTML.replace("{{SUB_PATH}}", &state.sub_path))
+}
+
+// Renders the subscription URL for the given token as a QR code
+async fn handle_qr(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    check_token(&state, &params)?;
+
+    let mut query = url::form_urlencoded::Serializer::new(String::new());
+    query.append_pair("token", &state.sub_uuid);
+    let sub_url = format!("{}{}?{}", base_url(&state, &headers)?, state.sub_path, query.finish());
+    let png = qr_png(&sub_url)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to render QR code: {}", e)))?;
+
+    let mut headers = HeaderMap::new();
+    headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("image/png"));
+    Ok((headers, png))
+}
+
+// Pixels per QR module, and the width of the blank border scanners need, in modules
+const QR_SCALE: usize = 8;
+const QR_QUIET_ZONE: usize = 4;
+
+fn qr_png(data: &str) -> anyhow::Result<Vec<u8>> {
+    let code = qrcode::QrCode::new(data.as_bytes())?;
+    let width = code.width();
+    let size = (width + 2 * QR_QUIET_ZONE) * QR_SCALE;
+
+    // 8-bit grayscale, white background with black modules
+    let mut pixels = vec![255u8; size * size];
+    for (i, color) in code.to_colors().into_iter().enumerate() {
+        if color != qrcode::Color::Dark {
+            continue;
+        }
+        let (x, y) = ((i % width + QR_QUIET_ZONE) * QR_SCALE, (i / width + QR_QUIET_ZONE) * QR_SCALE);
+        for row in y..y + QR_SCALE {
+            pixels[row * size + x..row * size + x + QR_SCALE].fill(0);
+        }
+    }
+
+    let mut png = Vec::new();
+    let mut encoder = png::Encoder::new(&mut png, size as u32, size as u32);
+    encoder.set_color(png::ColorType::Grayscale);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.write_header()?.write_image_data(&pixels)?;
+    Ok(png)
+}
+
+// Serves only the `proxies:` list, for Clash configs that use this server as a proxy provider
+async fn handle_provider(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    check_token(&state, &params)?;
+    cached_response(&state, "provider", &headers, &params, provider_response(&state, &headers, &params)).await
+}
+
+async fn provider_response(
+    state: &AppState,
+    headers: &HeaderMap,
+    params: &HashMap<String, String>,
+) -> Result<(HeaderMap, String), (StatusCode, String)> {
+    let options = request_options(state, params, &user_agent(headers))?;
+    let LoadedLinks { links: raw_links, upstream_error } = load_links(state, params).await?;
+    let mut extra_proxies = load_wireguard(state).await?;
+    extra_proxies.extend(load_imported(state).await?);
+
+    let (yaml_content, count, total, warnings) = tokio::task::spawn_blocking(move || {
+        let (proxies, total, warnings) = limited_proxies(raw_links, extra_proxies, &options);
+        let count = proxies.len();
+        clash_generator::render_provider_yaml(proxies).map(|yaml| (yaml, count, total, warnings))
+    })
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to generate subscription: {}", e)))?
+    .map_err(|e| generation_error_response(&e))?;
+
+    let mut headers = HeaderMap::new();
+    headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("text/yaml; charset=utf-8"));
+    if count < total {
+        headers.insert("x-proxy-count", HeaderValue::from_str(&format!("{}/{}", count, total)).unwrap());
+    }
+    if !warnings.is_empty() {
+        headers.insert("x-parse-warnings", HeaderValue::from(warnings.len()));
+    }
+    if upstream_error {
+        headers.insert("x-upstream-error", HeaderValue::from_static("true"));
+    }
+
+    Ok((headers, yaml_content))
+}
+
+// Lists the proxies parsed with the request's options and every link that failed, for
+// diagnosing what clients are served. Only routed with --enable-debug, and never cached.
+async fn handle_debug(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Query(params): Query<HashMap<String, String>>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    check_token(&state, &params)?;
+    let options = request_options(&state, &params, &user_agent(&headers))?;
+    let LoadedLinks { links: raw_links, upstream_error } = load_links(&state, &params).await?;
+    let mut extra_proxies = load_wireguard(&state).await?;
+    extra_proxies.extend(load_imported(&state).await?);
+
+    let body = tokio::task::spawn_blocking(move || {
+        let mut parse_errors = Vec::new();
+        let proxies = clash_generator::collect_proxies(raw_links, extra_proxies, &options, &mut parse_errors);
+        let failures: Vec<_> = parse_errors
+            .iter()
+            .map(|e| serde_json::json!({ "link": e.link(), "error": e.to_string() }))
+            .collect();
+        serde_json::to_string_pretty(&serde_json::json!({
+            "count": proxies.len(),
+            "proxies": proxies,
+            "failures": failures,
+            "upstream_error": upstream_error,
+        }))
+    })
+    .await
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to list proxies: {}", e)))?
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("Failed to serialize proxies: {}", e)))?;
+
+    let mut headers = HeaderMap::new();
+    headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    Ok((headers, body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    // Server state with every optional feature off, serving `files`
+    fn test_state(files: Vec<PathBuf>) -> AppState {
+        AppState {
+            file_paths: files,
+            stdin_links: None,
+            wireguard_paths: Vec::new(),
+            import_path: None,
+            sub_uuid: "test-token".to_string(),
+            base_path: String::new(),
+            sub_path: "/sub".to_string(),
+            template_path: None,
+            templates: HashMap::new(),
+            remote_template: None,
+            gen_options: clash_generator::ClashGenOptions::default(),
+            default_format: OutputFormat::Base64,
+            target: None,
+            provider_mode: false,
+            max_file_size: 1024 * 1024,
+            max_nodes: 10_000,
+            base64_wrap: None,
+            update_interval: HeaderValue::from(86400u64),
+            http_client: reqwest::Client::new(),
+            cache: Arc::new(SubCache::new(Duration::ZERO)),
+            upstream_bodies: Arc::new(RwLock::new(HashMap::new())),
+            request_timeout: Duration::from_secs(30),
+        }
+    }
+
+    fn params(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    #[test]
+    fn schemes_param_restricts_parsed_links() {
+        let state = test_state(Vec::new());
+        let options = request_options(&state, &params(&[("schemes", "ss, trojan")]), "").unwrap();
+        assert_eq!(options.allowed_schemes, Some(vec!["ss".to_string(), "trojan".to_string()]));
+        assert_eq!(request_options(&state, &params(&[]), "").unwrap().allowed_schemes, None);
+    }
+
+    const WIREGUARD_CONF: &str = "[Interface]\nPrivateKey = yAnz5TF+lXXJte14tji3zlMNq+hd2rYUIgJBgB3fBmk=\nAddress = 10.0.0.2/32\n\n[Peer]\nPublicKey = xTIBA5rboUvnH4htodjb6e697QjLERt1NAB4mZqp8Dg=\nEndpoint = wg.example.com:51820\nAllowedIPs = 0.0.0.0/0\n";
+
+    // Writes `content` to a file in the temp dir; `name` must be unique across tests
+    fn temp_file(name: &str, content: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("txt2sub-test-{}-{}", std::process::id(), name));
+        std::fs::write(&path, content).unwrap();
+        path
+    }
+
+    // The `proxies` list of a generated Clash config
+    fn clash_proxies(yaml: &str) -> Vec<serde_yaml::Value> {
+        clash_doc(yaml)["proxies"].as_sequence().unwrap().clone()
+    }
+
+    #[tokio::test]
+    async fn udp_param_overrides_every_proxy() {
+        let links = temp_file("udp-links.txt", "trojan://secret@tr.example.com:443#SG-1\n");
+        let mut state = test_state(vec![links]);
+        state.wireguard_paths = vec![temp_file("udp-wg.conf", WIREGUARD_CONF)];
+        state.gen_options.udp = Some(false);
+
+        let (_, yaml) = subscription_response(&state, &HeaderMap::new(), &params(&[("format", "clash"), ("udp", "true")]))
+            .await
+            .unwrap();
+        let proxies = clash_proxies(&yaml);
+        assert_eq!(proxies.len(), 2);
+        assert!(proxies.iter().all(|p| p["udp"] == serde_yaml::Value::Bool(true)));
+
+        let (_, yaml) = subscription_response(&state, &HeaderMap::new(), &params(&[("format", "clash")])).await.unwrap();
+        assert!(clash_proxies(&yaml).iter().all(|p| p["udp"] == serde_yaml::Value::Bool(false)));
+
+        let err = request_options(&state, &params(&[("udp", "maybe")]), "").unwrap_err();
+        assert_eq!(err.0, StatusCode::BAD_REQUEST);
+    }
+
+    fn clash_doc(yaml: &str) -> serde_yaml::Value {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    #[tokio::test]
+    async fn template_param_selects_named_template() {
+        let template = |log_level: &str| format!("log-level: {}\nproxy-groups:\n  - name: PROXY\n    type: select\n    proxies: []\n", log_level);
+        let links = temp_file("template-links.txt", "trojan://secret@tr.example.com:443#SG-1\n");
+        let mut state = test_state(vec![links]);
+        state.templates.insert("router".to_string(), temp_file("template-router.yaml", &template("warning")));
+        let request = |name: &str| params(&[("format", "clash"), ("template", name)]);
+
+        // Without a default template, unknown names get the built-in config
+        let (_, yaml) = subscription_response(&state, &HeaderMap::new(), &request("router")).await.unwrap();
+        assert_eq!(clash_doc(&yaml)["log-level"], "warning");
+        let (_, yaml) = subscription_response(&state, &HeaderMap::new(), &request("phone")).await.unwrap();
+        assert_eq!(clash_doc(&yaml)["proxy-groups"][0]["name"], "Proxy");
+
+        state.template_path = Some(temp_file("template-default.yaml", &template("info")));
+        let (_, yaml) = subscription_response(&state, &HeaderMap::new(), &request("phone")).await.unwrap();
+        assert_eq!(clash_doc(&yaml)["log-level"], "info");
+        let (_, yaml) = subscription_response(&state, &HeaderMap::new(), &request("router")).await.unwrap();
+        assert_eq!(clash_doc(&yaml)["log-level"], "warning");
+    }
+
+    fn lines(items: &[&str]) -> Vec<String> {
+        items.iter().map(|l| l.to_string()).collect()
+    }
+
+    #[test]
+    fn cap_nodes_keeps_the_first_links_and_all_directives() {
+        let links = lines(&["a://1", "# group: B", "b://2", "b://3", "# group: C", "c://4"]);
+        assert_eq!(cap_nodes(links.clone(), 2), lines(&["a://1", "# group: B", "b://2", "# group: C"]));
+        assert_eq!(cap_nodes(links.clone(), 4), links);
+    }
+
+    #[tokio::test]
+    async fn oversized_links_file_is_refused() {
+        let links = temp_file("oversized-links.txt", &"trojan://secret@tr.example.com:443#SG-1\n".repeat(8));
+        assert!(read_links_file(&links, 1024).await.is_ok());
+        let err = read_links_file(&links, 64).await.unwrap_err();
+        assert!(err.to_string().contains("--max-file-size"), "{}", err);
+
+        let mut state = test_state(vec![links]);
+        state.max_file_size = 64;
+        let (status, _) = subscription_response(&state, &HeaderMap::new(), &params(&[])).await.unwrap_err();
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[tokio::test]
+    async fn slow_requests_time_out_with_504() {
+        use tower::ServiceExt;
+
+        let app: Router = Router::new()
+            .route("/slow", get(|| async { tokio::time::sleep(Duration::from_secs(10)).await }))
+            .route("/fast", get(|| async { "ok" }));
+        let app = with_limits(app, Duration::from_millis(100));
+        let request = |uri: &str| axum::http::Request::get(uri).body(axum::body::Body::empty()).unwrap();
+
+        let response = app.clone().oneshot(request("/slow")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+        let response = app.oneshot(request("/fast")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn every_option_reads_its_env_var() {
+        // Checked on the clap definition so no test has to touch the process environment
+        let command = Args::command();
+        for arg in command.get_arguments().filter(|arg| arg.get_long().is_some()) {
+            let long = arg.get_long().unwrap();
+            if matches!(long, "help" | "version") {
+                continue;
+            }
+            let expected = format!("TXT2SUB_{}", long.to_uppercase().replace('-', "_"));
+            assert_eq!(arg.get_env().and_then(|env| env.to_str()), Some(expected.as_str()), "--{long}");
+        }
+        for (id, env) in [("file", "TXT2SUB_FILE"), ("port", "TXT2SUB_PORT"), ("uuid", "TXT2SUB_UUID")] {
+            let arg = command.get_arguments().find(|arg| arg.get_id() == id).unwrap();
+            assert_eq!(arg.get_env().and_then(|env| env.to_str()), Some(env));
+        }
+    }
+
+    const TWO_LINKS: &str = "trojan://secret@a.example.com:443#SG-1\ntrojan://secret@b.example.com:443#SG-2\n";
+
+    #[tokio::test]
+    async fn base64_links_file_is_expanded() {
+        let encoded = general_purpose::STANDARD.encode(TWO_LINKS);
+        let wrapped = wrap_lines(&encoded, 16);
+        assert!(wrapped.lines().count() > 1);
+        let path = temp_file("base64-links.txt", &wrapped);
+        assert_eq!(read_links_file(&path, 1024).await.unwrap(), TWO_LINKS);
+
+        // Plain link files are read as they are
+        let path = temp_file("plain-links.txt", TWO_LINKS);
+        assert_eq!(read_links_file(&path, 1024).await.unwrap(), TWO_LINKS);
+        assert_eq!(decode_base64_links("not base64 at all"), None);
+    }
+
+    #[tokio::test]
+    async fn node_count_header_counts_parsed_links() {
+        let content = format!("{}vmess://not-base64!\ntrojan://secret@c.example.com:443#SG-3\n", TWO_LINKS);
+        let state = test_state(vec![temp_file("node-count-links.txt", &content)]);
+        for format in ["clash", "base64", "raw"] {
+            let (headers, _) = subscription_response(&state, &HeaderMap::new(), &params(&[("format", format)])).await.unwrap();
+            assert_eq!(headers["x-node-count"], "3", "{}", format);
+        }
+    }
+
+    // Member names of the group called `name` in a generated Clash config
+    fn group_members(doc: &serde_yaml::Value, name: &str) -> Vec<String> {
+        let group = doc["proxy-groups"].as_sequence().unwrap().iter().find(|g| g["name"] == name).unwrap();
+        group["proxies"].as_sequence().unwrap().iter().map(|m| m.as_str().unwrap().to_string()).collect()
+    }
+
+    #[tokio::test]
+    async fn multiple_files_are_concatenated() {
+        let first = temp_file("multi-first.txt", "# group: Streaming\ntrojan://secret@a.example.com:443#Netflix\n");
+        let second = temp_file("multi-second.txt", "trojan://secret@b.example.com:443#Plain\n");
+        let mut state = test_state(vec![first, second]);
+        state.wireguard_paths = vec![temp_file("multi-home.conf", WIREGUARD_CONF), temp_file("multi-office.conf", WIREGUARD_CONF)];
+        state.template_path = Some(temp_file(
+            "multi-template.yaml",
+            "proxy-groups:\n  - {name: PROXY, type: select, proxies: []}\n  - {name: Streaming, type: select, proxies: []}\n",
+        ));
+
+        let (_, yaml) = subscription_response(&state, &HeaderMap::new(), &params(&[("format", "clash")])).await.unwrap();
+        let doc = clash_doc(&yaml);
+        let names: Vec<_> = clash_proxies(&yaml).iter().map(|p| p["name"].as_str().unwrap().to_string()).collect();
+        let stem = |name: &str| format!("txt2sub-test-{}-{}", std::process::id(), name);
+        assert_eq!(names, [stem("multi-home"), stem("multi-office"), "Netflix".to_string(), "Plain".to_string()]);
+        // A directive only covers the rest of its own file
+        assert_eq!(group_members(&doc, "Streaming"), ["Netflix"]);
+    }
+
+    async fn get_status(app: Router, uri: &str) -> StatusCode {
+        use tower::ServiceExt;
+
+        let request = axum::http::Request::get(uri).body(axum::body::Body::empty()).unwrap();
+        app.oneshot(request).await.unwrap().status()
+    }
+
+    #[tokio::test]
+    async fn base_path_nests_every_endpoint() {
+        let links = temp_file("base-path-links.txt", TWO_LINKS);
+        let app = app_router(Arc::new(test_state(vec![links.clone()])), false);
+        assert_eq!(get_status(app.clone(), "/sub?token=test-token").await, StatusCode::OK);
+        assert_eq!(get_status(app, "/txt2sub/sub?token=test-token").await, StatusCode::NOT_FOUND);
+
+        let mut state = test_state(vec![links]);
+        state.base_path = "/txt2sub".to_string();
+        let app = app_router(Arc::new(state), false);
+        assert_eq!(get_status(app.clone(), "/txt2sub/sub?token=test-token").await, StatusCode::OK);
+        assert_eq!(get_status(app.clone(), "/txt2sub").await, StatusCode::OK);
+        assert_eq!(get_status(app, "/sub?token=test-token").await, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn target_and_format_params_pick_the_output() {
+        let content = "trojan://secret@a.example.com:443#SG-1\nvless://2f0c6a3e-8b1d-4c5e-9f7a-3b2c1d0e9f8a@b.example.com:443?security=tls#HK-1\n";
+        let state = test_state(vec![temp_file("target-links.txt", content)]);
+        let respond = |pairs: &'static [(&'static str, &'static str)], user_agent: &'static str| {
+            let state = &state;
+            async move {
+                let mut headers = HeaderMap::new();
+                headers.insert(header::USER_AGENT, HeaderValue::from_static(user_agent));
+                subscription_response(state, &headers, &params(pairs)).await
+            }
+        };
+
+        for pairs in [&[("target", "clash")][..], &[("format", "clash")][..]] {
+            let (_, body) = respond(pairs, "").await.unwrap();
+            assert_eq!(clash_proxies(&body).len(), 2, "{:?}", pairs);
+        }
+        let (_, body) = respond(&[("target", "raw")], "").await.unwrap();
+        assert_eq!(body.lines().count(), 2);
+        let (_, body) = respond(&[("target", "base64")], "").await.unwrap();
+        assert_eq!(decode_base64_links(&body).unwrap().lines().count(), 2);
+        let (_, body) = respond(&[("flag", "clash")], "").await.unwrap();
+        assert_eq!(clash_proxies(&body).len(), 2);
+
+        // A stock Clash User-Agent loses the vless node unless the target names Clash.Meta
+        let (_, body) = respond(&[("target", "clash")], "clash/1.18").await.unwrap();
+        assert_eq!(clash_proxies(&body).len(), 1);
+        for meta in [&[("target", "meta")][..], &[("target", "mihomo")][..]] {
+            let (_, body) = respond(meta, "clash/1.18").await.unwrap();
+            assert_eq!(clash_proxies(&body).len(), 2);
+        }
+
+        for (pairs, status) in [
+            (&[("target", "singbox")][..], StatusCode::BAD_REQUEST),
+            (&[("format", "surge")][..], StatusCode::BAD_REQUEST),
+            (&[("target", "bogus")][..], StatusCode::BAD_REQUEST),
+            (&[("format", "meta")][..], StatusCode::BAD_REQUEST),
+        ] {
+            assert_eq!(respond(pairs, "").await.unwrap_err().0, status, "{:?}", pairs);
+        }
+    }
+
+    async fn respond(state: &AppState, pairs: &[(&str, &str)]) -> String {
+        subscription_response(state, &HeaderMap::new(), &params(pairs)).await.unwrap().1
+    }
+
+    #[tokio::test]
+    async fn base64_output_wraps_at_the_requested_width() {
+        let mut state = test_state(vec![temp_file("wrap-links.txt", TWO_LINKS)]);
+
+        let single = respond(&state, &[("format", "base64")]).await;
+        assert_eq!(single.lines().count(), 1);
+        let wrapped = respond(&state, &[("format", "base64"), ("wrap", "76")]).await;
+        let lines: Vec<_> = wrapped.lines().collect();
+        assert!(lines.len() > 1);
+        assert!(lines.iter().all(|l| l.len() <= 76));
+        assert!(lines[..lines.len() - 1].iter().all(|l| l.len() == 76));
+        assert_eq!(lines.concat(), single.trim_end());
+        assert_eq!(decode_base64_links(&wrapped).unwrap(), TWO_LINKS.trim_end());
+
+        // --base64-wrap applies when the request has no `wrap`, and `wrap=0` turns it off
+        state.base64_wrap = Some(20);
+        assert!(respond(&state, &[("format", "base64")]).await.lines().all(|l| l.len() <= 20));
+        assert_eq!(respond(&state, &[("format", "base64"), ("wrap", "0")]).await.lines().count(), 1);
+    }
+
+    const SAMPLE_CONFIG: &str = r#"
+rename:
+  - "^(\\w+) Premium=>$1"
+include: "^(HK|JP)"
+exclude: "Test"
+sort: name
+proxy-group-name: Select
+auto-group-name: Fastest
+rules-preset: bypass-cn
+dedup: true
+"#;
+
+    #[test]
+    fn config_file_settings_take_effect() {
+        let config = ConfigFile::load(&temp_file("sample-config.yaml", SAMPLE_CONFIG)).unwrap();
+        let options = gen_options(&Args::try_parse_from(["txt2sub"]).unwrap(), config);
+        let links = [
+            "trojan://secret@a.example.com:443#JP Premium",
+            "trojan://secret@b.example.com:443#HK Premium",
+            "trojan://secret@b.example.com:443#HK Backup",
+            "trojan://secret@c.example.com:443#HK Test",
+            "trojan://secret@d.example.com:443#US Premium",
+        ];
+        let links = links.iter().map(|l| l.to_string()).collect();
+        let yaml = clash_generator::generate_clash_yaml(links, Vec::new(), None, &options).unwrap();
+        let doc = clash_doc(&yaml);
+
+        let names: Vec<_> = clash_proxies(&yaml).iter().map(|p| p["name"].as_str().unwrap().to_string()).collect();
+        assert_eq!(names, ["HK", "JP"]);
+        assert_eq!(group_members(&doc, "Select")[..3], ["Fastest", "HK", "JP"]);
+        assert_eq!(group_members(&doc, "Fastest"), ["HK", "JP"]);
+        let rules = doc["rules"].as_sequence().unwrap();
+        assert!(rules.contains(&"GEOIP,CN,DIRECT".into()));
+        assert_eq!(rules.last().unwrap(), "MATCH,Select");
+
+        // Flags win over the config file
+        let config = ConfigFile::load(&temp_file("sample-config.yaml", SAMPLE_CONFIG)).unwrap();
+        let args = Args::try_parse_from(["txt2sub", "--proxy-group-name", "Manual", "--sort", "server", "--dedup=false"]).unwrap();
+        let options = gen_options(&args, config);
+        assert_eq!(options.proxy_group(), "Manual");
+        assert_eq!(options.auto_group(), "Fastest");
+        assert_eq!(options.sort, Some(clash_generator::SortKey::Server));
+        assert_eq!(options.dedup, None);
+    }
+
+    #[tokio::test]
+    async fn cors_origin_header_on_success_and_403() {
+        use tower::ServiceExt;
+
+        let state = Arc::new(test_state(vec![temp_file("cors-links.txt", TWO_LINKS)]));
+        let request = |uri: &str| {
+            axum::http::Request::get(uri)
+                .header(header::ORIGIN, "https://dash.example.com")
+                .body(axum::body::Body::empty())
+                .unwrap()
+        };
+
+        let app = app_router(state.clone(), false).layer(cors_layer(&[parse_cors_origin("https://dash.example.com").unwrap()]));
+        for (uri, status) in [("/sub?token=test-token", StatusCode::OK), ("/sub?token=wrong", StatusCode::FORBIDDEN)] {
+            let response = app.clone().oneshot(request(uri)).await.unwrap();
+            assert_eq!(response.status(), status);
+            assert_eq!(response.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN], "https://dash.example.com");
+        }
+
+        let app = app_router(state.clone(), false).layer(cors_layer(&[parse_cors_origin("*").unwrap()]));
+        let response = app.oneshot(request("/sub?token=test-token")).await.unwrap();
+        assert_eq!(response.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN], "*");
+
+        // Without --cors-origin no CORS headers are sent
+        let response = app_router(state, false).oneshot(request("/sub?token=test-token")).await.unwrap();
+        assert!(response.headers().get(header::ACCESS_CONTROL_ALLOW_ORIGIN).is_none());
+    }
+
+    #[tokio::test]
+    async fn validate_json_report_lists_the_bad_line() {
+        let content = format!("# group: Main\n{}\nvmess://not-base64!\n", TWO_LINKS);
+        let path = temp_file("validate-links.txt", &content);
+        let args = Args::try_parse_from(["txt2sub", "--validate", "--json", "--file", path.to_str().unwrap()]).unwrap();
+        let report = check_report(&args).await.unwrap().to_json();
+        let expected = serde_json::json!({
+            "total": 3,
+            "parsed": 2,
+            "failed": 1,
+            "protocols": {
+                "trojan": { "total": 2, "parsed": 2, "failed": 0 },
+                "vmess": { "total": 1, "parsed": 0, "failed": 1 },
+            },
+            "failures": [{
+                "file": path,
+                "line_number": 5,
+                "content": "vmess://not-base64!",
+                "protocol": "vmess",
+                "reason": "payload is not valid base64",
+            }],
+        });
+        assert_eq!(report, expected);
+    }
+
+    #[tokio::test]
+    async fn limit_counts_only_links_that_parse() {
+        let content = format!("vmess://not-base64!\n{}trojan://secret@c.example.com:443#SG-3\n", TWO_LINKS);
+        let state = test_state(vec![temp_file("limit-links.txt", &content)]);
+        for format in ["raw", "base64"] {
+            let (headers, body) = subscription_response(&state, &HeaderMap::new(), &params(&[("format", format), ("limit", "2")]))
+                .await
+                .unwrap();
+            assert_eq!(headers["x-node-count"], "2", "{}", format);
+            let links = if format == "raw" { body } else { decode_base64_links(&body).unwrap() };
+            assert!(links.ends_with("#SG-2"), "{}: {}", format, links);
+        }
+    }
+
+    #[tokio::test]
+    async fn etag_ignores_the_timestamp_and_answers_304() {
+        use tower::ServiceExt;
+
+        let stamped = |at: &str| format!("# Generated by txt2sub v0.1.0 at {at}\n# Nodes: 2\nproxies: []\n");
+        assert_eq!(body_etag(&stamped("2025-01-01T00:00:00Z")), body_etag(&stamped("2025-01-01T00:00:05Z")));
+        assert_ne!(body_etag(&stamped("2025-01-01T00:00:00Z")), body_etag("proxies: []\n"));
+
+        let app = app_router(Arc::new(test_state(vec![temp_file("etag-links.txt", TWO_LINKS)])), false);
+        let get = |if_none_match: Option<&str>| {
+            let mut request = axum::http::Request::get("/sub?token=test-token&flag=clash");
+            if let Some(tag) = if_none_match {
+                request = request.header(header::IF_NONE_MATCH, tag);
+            }
+            app.clone().oneshot(request.body(axum::body::Body::empty()).unwrap())
+        };
+        let first = get(None).await.unwrap();
+        assert_eq!(first.status(), StatusCode::OK);
+        let etag = first.headers()[header::ETAG].to_str().unwrap().to_string();
+
+        // The cache is off, so this regenerates the config with a new timestamp
+        let revalidated = get(Some(&etag)).await.unwrap();
+        assert_eq!(revalidated.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(revalidated.headers()[header::ETAG], etag.as_str());
+        assert_eq!(get(Some(&format!("\"stale\", W/{etag}"))).await.unwrap().status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(get(Some("\"stale\"")).await.unwrap().status(), StatusCode::OK);
+    }
 }